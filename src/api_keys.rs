@@ -0,0 +1,211 @@
+//! API-routes to manage scoped API keys for bots and integrations.
+//!
+//! The key itself is only shown once, when it is created. The auth service
+//! only stores its SHA-256 digest.
+use chrono::prelude::*;
+use rocket_contrib::Json;
+
+use datatypes::auth::responses::Role;
+use datatypes::content::responses::ContentError;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::UserId;
+
+use crate::auth::connect_to_auth;
+use crate::comms::auth::{
+    ApiKeyId, ApiKeyPayload, ApiScope, CreateApiKeyPayload, RevokeApiKeyPayload,
+};
+use crate::crypto;
+use crate::error::ApiResult;
+use crate::guards::AuthenticatedUser;
+
+/// Requests which create or revoke API keys
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ApiKeyRequest {
+    CreateApiKey(CreateApiKeyForm),
+    RevokeApiKey(RevokeApiKeyForm),
+}
+
+/// A new API key
+///
+/// Admins may set `user_id` to create a key on behalf of another user.
+#[derive(Deserialize, Debug)]
+pub struct CreateApiKeyForm {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Seconds since the unix epoch
+    pub expires: Option<i64>,
+    pub user_id: Option<UserId>,
+}
+
+/// The key to revoke
+///
+/// Admins may set `user_id` to revoke a key of another user.
+#[derive(Deserialize, Debug)]
+pub struct RevokeApiKeyForm {
+    pub id: ApiKeyId,
+    pub user_id: Option<UserId>,
+}
+
+/// A newly created key together with the key itself
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NewApiKeyPayload {
+    pub key: String,
+    pub info: ApiKeyPayload,
+}
+
+/// The outcome of a successful API key request
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ApiKeySuccess {
+    ApiKeys(Vec<ApiKeyPayload>),
+    ApiKeyCreated(NewApiKeyPayload),
+    ApiKeyRevoked,
+}
+
+/// Only admins may act on behalf of other users
+fn target_user(
+    user: &AuthenticatedUser,
+    user_id: Option<UserId>,
+) -> Result<UserId, ResponseError> {
+    match user_id {
+        Some(user_id) if user_id != user.id => {
+            user.require(Role::Admin)?;
+            Ok(user_id)
+        }
+        _ => Ok(user.id),
+    }
+}
+
+/// Get the API keys of the logged in user
+///
+/// # Example
+///
+/// ## Query
+///
+/// ´´´text
+/// localhost:9234/api/keys
+/// ´´´
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "type": "API_KEYS",
+///     "payload": [{
+///         "id": 2,
+///         "user_id": 4,
+///         "name": "announcement bot",
+///         "scopes": [{ "type": "POST_THREADS" }],
+///         "created": 1539950400,
+///         "expires": null
+///     }]
+/// }
+/// ´´´
+#[get("/keys")]
+pub fn get_api_keys(user: AuthenticatedUser) -> ApiResult<ApiKeySuccess> {
+    user.session_token()?;
+
+    connect_to_auth()?
+        .get_api_keys(user.id)
+        .map(|v| {
+            info!("Returning success from 'get-api-keys' request");
+            Json(ApiKeySuccess::ApiKeys(v))
+        }).map_err(|e| {
+            error!("Unable to 'get-api-keys': {:?}", e);
+            e.into()
+        })
+}
+
+/// Create or revoke an API key
+///
+/// API keys cannot be used to manage API keys.
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "type": "CREATE_API_KEY",
+///     "payload": {
+///         "name": "moderation helper",
+///         "scopes": [{ "type": "MODERATE_CATEGORY", "payload": 3 }],
+///         "expires": 1571486400
+///     }
+/// }
+/// ´´´
+///
+/// The key is sent as `Authorization: Bearer <key>` and is only shown in the
+/// response to this request.
+///
+/// ´´´json
+/// {
+///     "type": "API_KEY_CREATED",
+///     "payload": {
+///         "key": "sg_3c2f...",
+///         "info": { ... }
+///     }
+/// }
+/// ´´´
+///
+/// ´´´json
+/// {
+///     "type": "REVOKE_API_KEY",
+///     "payload": {
+///         "id": 2
+///     }
+/// }
+/// ´´´
+#[post("/keys", format = "application/json", data = "<req>")]
+pub fn post_api_keys(
+    user: AuthenticatedUser,
+    req: Option<Json<ApiKeyRequest>>,
+) -> ApiResult<ApiKeySuccess> {
+    use self::ApiKeyRequest::*;
+
+    let req = req.ok_or(ContentError::InvalidContent)?; // If invalid request give error.
+    user.session_token()?;
+
+    match req.into_inner() {
+        CreateApiKey(p) => {
+            let user_id = target_user(&user, p.user_id)?;
+            if p.expires.map_or(false, |expires| expires <= Utc::now().timestamp()) {
+                return Err(ContentError::InvalidContent.into());
+            }
+
+            let key = format!("sg_{}", crypto::random_hex(32));
+            let payload = CreateApiKeyPayload {
+                user_id,
+                name: p.name,
+                scopes: p.scopes,
+                expires: p.expires,
+                key_hash: crypto::sha256_hex(key.as_bytes()),
+            };
+
+            connect_to_auth()?
+                .create_api_key(payload)
+                .map(|info| {
+                    info!("User ({:?}) created API key {:?}", user.id, info.id);
+                    Json(ApiKeySuccess::ApiKeyCreated(NewApiKeyPayload { key, info }))
+                }).map_err(|e| {
+                    error!("Unable to 'create-api-key': {:?}", e);
+                    e.into()
+                })
+        }
+        RevokeApiKey(p) => {
+            let payload = RevokeApiKeyPayload {
+                user_id: target_user(&user, p.user_id)?,
+                id: p.id,
+            };
+
+            connect_to_auth()?
+                .revoke_api_key(payload)
+                .map(|_| {
+                    info!("User ({:?}) revoked API key {:?}", user.id, p.id);
+                    Json(ApiKeySuccess::ApiKeyRevoked)
+                }).map_err(|e| {
+                    error!("Unable to 'revoke-api-key': {:?}", e);
+                    e.into()
+                })
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use rocket::http::Cookies;
+use rocket::State;
 use rocket_contrib::Json;
 
 use std::convert::TryInto;
@@ -9,12 +10,30 @@ use tarpc::sync::client::{ClientExt, Options};
 use datatypes::auth::requests::RegisterUserPayload;
 use datatypes::auth::requests::{AuthRequest, SetUserRolePayload};
 use datatypes::auth::responses::{AuthError, AuthSuccess, Role};
+use datatypes::content::requests::GetUserPayload;
+use datatypes::content::responses::UserPayload;
 use datatypes::error::ResponseError;
-use datatypes::valid::token::USER_TOKEN_NAME;
+use datatypes::valid::fields::{Email, PlainPassword};
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::{Token, USER_TOKEN_NAME};
 
 use crate::comms::auth::SyncClient as AuthClient;
+use crate::comms::auth::{
+    ChangePasswordPayload, RequestPasswordResetPayload, ResetPasswordPayload,
+    SessionContextPayload,
+};
 use crate::content::connect_to_controller;
-use crate::JsonResponseResult;
+use crate::error::{ApiResult, LegacyResult};
+use crate::guards::AuthenticatedUser;
+use crate::mail::{Mail, MailSender};
+use crate::password_policy;
+use crate::pow::{self, ProofOfWork};
+use crate::registration::{self, InviteCode};
+use crate::session_binding;
+use crate::sessions::UserAgent;
+use crate::token_cache::TOKEN_CACHE;
+use crate::totp;
+use crate::users;
 
 lazy_static! {
     static ref AUTH_IP: SocketAddr = match std::env::var("AUTH_ADDRESS") {
@@ -39,6 +58,20 @@ pub fn connect_to_auth() -> Result<AuthClient, ResponseError> {
     })
 }
 
+/// Get the id and role of the user owning `token`
+///
+/// Answers from the token cache if possible and asks the auth service
+/// otherwise.
+pub fn get_user(token: Token) -> Result<(UserId, Role), ResponseError> {
+    if let Some(user) = TOKEN_CACHE.get(&token) {
+        return Ok(user);
+    }
+
+    let user = connect_to_auth()?.get_user(token.clone())?;
+    TOKEN_CACHE.insert(token, user);
+    Ok(user)
+}
+
 /// Authenticate or deauthenticate user
 ///
 /// Uses a strict JSON format to conway actions.
@@ -83,65 +116,127 @@ pub fn connect_to_auth() -> Result<AuthClient, ResponseError> {
 /// ```
 ///
 /// The possible types are defined in [`AuthError`](../responses/enum.AuthError.html)
+///
+/// If proof-of-work is enabled, authentication and registration requests must
+/// carry the solution to a challenge from `/api/auth/challenge` in the
+/// `X-Proof-Of-Work` header.
+///
+/// The password of a new user has to satisfy the password policy, see
+/// `password_policy`.
+///
+/// While registration is invite-only, registration requests must carry an
+/// invite code in the `X-Invite-Code` header.
+///
+/// If the user has two-factor authentication enabled the response is a
+/// `TOTP_REQUIRED` error with a challenge, which has to be answered through
+/// `/api/auth/totp` to get the session cookie.
+///
+/// Errors are sent with status 200, like they always were.
 #[post("/auth", format = "application/json", data = "<req>")]
 pub fn auth(
     mut cookies: Cookies,
+    remote: Option<SocketAddr>,
+    user_agent: Option<UserAgent>,
+    proof_of_work: Option<ProofOfWork>,
+    invite: Option<InviteCode>,
+    req: Option<Json<AuthRequest>>,
+) -> LegacyResult<AuthSuccess> {
+    handle_auth(&mut cookies, remote, user_agent, proof_of_work, invite, req).map_err(Json)
+}
+
+fn handle_auth(
+    cookies: &mut Cookies,
+    remote: Option<SocketAddr>,
+    user_agent: Option<UserAgent>,
+    proof_of_work: Option<ProofOfWork>,
+    invite: Option<InviteCode>,
     req: Option<Json<AuthRequest>>,
-) -> JsonResponseResult<AuthSuccess> {
+) -> ApiResult<AuthSuccess> {
     use datatypes::auth::requests::AuthRequest::*;
 
-    let req = req
-        .ok_or(AuthError::InvalidCredentials)
-        .map_err(|e| Json(e.into()))?; // If invalid request query.
+    let req = req.ok_or(AuthError::InvalidCredentials)?; // If invalid request query.
 
     match req.into_inner() {
         Authenticate(p) => {
+            pow::verify(proof_of_work, remote)?;
+
             let username = p.username.clone();
-            connect_to_auth()
-                .map_err(Json)?
-                .authenticate(p)
-                .map(|token| {
-                    info!("User '{}' authenticated successfully", &username);
+            let auth = connect_to_auth()?;
+            let token = auth.authenticate(p).map_err(|e| {
+                error!("Unable to 'authenticate': {:?}", e);
+                e
+            })?;
+            let (id, role) = get_user(token.clone())?;
 
-                    cookies.add_private(token.into());
+            // Remember where the session was created from so the user can
+            // recognize it later
+            let context = SessionContextPayload {
+                token,
+                ip: remote.map(|addr| addr.ip()),
+                user_agent: user_agent.map(|ua| ua.0),
+            };
 
-                    Json(AuthSuccess::Authenticated)
-                }).map_err(|e| {
-                    error!("Unable to 'authenticate': {:?}", e);
-                    Json(e.into())
-                })
+            // Hold back the session if a second factor is needed
+            totp::check_login(&auth, id, role, context.clone())?;
+
+            start_session(&auth, cookies, context);
+            info!("User '{}' authenticated successfully", &username);
+
+            Ok(Json(AuthSuccess::Authenticated))
         }
         Deauthenticate(_) => {
             let cookie = cookies
                 .get_private(USER_TOKEN_NAME)
-                .ok_or(Json(ResponseError::Unauthenticated))?;
+                .ok_or(ResponseError::Unauthenticated)?;
 
-            connect_to_auth()
-                .map_err(Json)?
-                .deauthenticate(cookie.clone().into())
+            let token: Token = cookie.clone().into();
+
+            connect_to_auth()?
+                .deauthenticate(token.clone())
                 .map(|_| {
+                    TOKEN_CACHE.invalidate_token(&token);
                     info!("User deauthenticated successfully");
                     cookies.remove_private(cookie);
+                    session_binding::unbind(cookies);
                     Json(AuthSuccess::Deauthenticated)
                 }).map_err(|e| {
                     error!("Unable to 'authenticate': {:?}", e);
-                    Json(e.into())
+                    e.into()
                 })
         }
         RegisterUser(p) => {
-            let user = connect_to_auth().map_err(Json)?.register(p).map_err(|e| {
-                error!("Auth: Unable to 'register': {:?}", e);
-                Json(e.into())
-            })?;
-            debug!("Auth: user registerd successfully");
+            pow::verify(proof_of_work, remote)?;
+            password_policy::verify(
+                p.password.as_ref(),
+                Some(p.username.as_ref()),
+                Some(p.email.as_ref()),
+            )?;
 
-            let _user = connect_to_controller()
-                .map_err(Json)?
-                .add_user(user)
+            let auth = connect_to_auth()?;
+            let reservation = registration::reserve(&auth, invite)?;
+
+            let registered = auth
+                .register(p)
                 .map_err(|e| {
-                    error!("Controller: Unable to add user: {:?}", e);
-                    Json(e.into())
-                })?;
+                    error!("Auth: Unable to 'register': {:?}", e);
+                    e.into()
+                }).and_then(|user| {
+                    debug!("Auth: user registerd successfully");
+                    users::provision(&auth, user)
+                });
+            let user = match registered {
+                Ok(user) => user,
+                Err(e) => {
+                    if let Some(reservation) = reservation {
+                        registration::release(&auth, reservation);
+                    }
+                    return Err(e.into());
+                }
+            };
+
+            if let Some(reservation) = reservation {
+                registration::redeem(&auth, reservation, user.id);
+            }
 
             debug!("Controller: Returning success from 'add_user' request");
             Ok(Json(AuthSuccess::UserRegistered))
@@ -149,20 +244,376 @@ pub fn auth(
     }
 }
 
-pub fn create_admin() {
-    let auth = connect_to_auth().expect("Unable to connect to the auth service");
-    let controller = connect_to_controller().expect("Unable to connect to the controller");
+/// Record where a session was created from and hand its token to the client
+///
+/// A session the client still holds is ended, so a session token planted
+/// before the login is of no use. Failing to record the session does not fail
+/// the login.
+pub fn start_session(auth: &AuthClient, cookies: &mut Cookies, context: SessionContextPayload) {
+    if let Some(cookie) = cookies.get_private(USER_TOKEN_NAME) {
+        let old_token: Token = cookie.into();
+        match auth.deauthenticate(old_token.clone()) {
+            Ok(()) => info!("Session event: ended previous session on login"),
+            Err(e) => debug!("Previous session already ended: {:?}", e),
+        }
+        TOKEN_CACHE.invalidate_token(&old_token);
+    }
 
-    println!("Creating admin account");
+    let token = context.token.clone();
+    match get_user(token.clone()) {
+        Ok((_, role)) => session_binding::bind(
+            cookies,
+            &token,
+            role,
+            context.ip,
+            context.user_agent.as_ref().map(String::as_str),
+        ),
+        Err(e) => warn!("Unable to bind session: {:?}", e),
+    }
+    if let Err(e) = auth.record_session(context) {
+        warn!("Unable to 'record_session': {:?}", e);
+    }
+    cookies.add_private(token.into());
+}
+
+/// Requests which change or reset the password of a user
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PasswordRequest {
+    ChangePassword(NewPasswordPayload),
+    RequestPasswordReset(PasswordResetEmailPayload),
+    ResetPassword(ResetPasswordPayload),
+}
+
+/// The current and the new password of a logged in user
+#[derive(Deserialize, Debug)]
+pub struct NewPasswordPayload {
+    pub old_password: PlainPassword,
+    pub new_password: PlainPassword,
+}
+
+/// The email of the account which should be reset
+#[derive(Deserialize, Debug)]
+pub struct PasswordResetEmailPayload {
+    pub email: Email,
+}
+
+/// The outcome of a successful `PasswordRequest`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PasswordSuccess {
+    PasswordChanged,
+    PasswordResetRequested,
+    PasswordReset,
+}
+
+/// Change or reset the password of a user
+///
+/// # Request objects
+///
+/// ## Change password request
+///
+/// Requires the user to be logged in and to know the current password.
+///
+/// ```json
+/// {
+///     "type": "CHANGE_PASSWORD",
+///     "payload": {
+///         "old_password": "secret",
+///         "new_password": "more secret"
+///     }
+/// }
+/// ```
+///
+/// ## Request password reset
+///
+/// Mails a reset token to the given address if it belongs to a user. The
+/// response is the same whether or not the address is known, so the endpoint
+/// cannot be used to enumerate users.
+///
+/// ```json
+/// {
+///     "type": "REQUEST_PASSWORD_RESET",
+///     "payload": {
+///         "email": "user@example.com"
+///     }
+/// }
+/// ```
+///
+/// ## Reset password
+///
+/// ```json
+/// {
+///     "type": "RESET_PASSWORD",
+///     "payload": {
+///         "reset_token": "<token from mail>",
+///         "new_password": "more secret"
+///     }
+/// }
+/// ```
+///
+/// # Response objects
+///
+/// ```json
+/// {
+///     "type": "PASSWORD_CHANGED",
+/// }
+/// ```
+///
+/// New passwords which do not satisfy the password policy are rejected with
+/// the reason:
+///
+/// ```json
+/// {
+///     "type": "PASSWORD_REJECTED",
+///     "payload": {
+///         "type": "TOO_SHORT",
+///         "payload": { "min_length": 10 }
+///     }
+/// }
+/// ```
+#[post("/auth/password", format = "application/json", data = "<req>")]
+pub fn password(
+    token: Option<Token>,
+    req: Option<Json<PasswordRequest>>,
+    mailer: State<Box<dyn MailSender>>,
+) -> ApiResult<PasswordSuccess> {
+    use self::PasswordRequest::*;
 
+    let req = req.ok_or(AuthError::InvalidCredentials)?; // If invalid request query.
+
+    match req.into_inner() {
+        ChangePassword(p) => {
+            let token = token.ok_or(ResponseError::Unauthenticated)?;
+
+            let auth = connect_to_auth()?;
+            let (id, _) = get_user(token.clone())?;
+            let account = auth.get_account(id)?;
+            password_policy::verify(
+                p.new_password.as_ref(),
+                Some(account.username.as_str()),
+                Some(account.email.as_str()),
+            )?;
+
+            let p = ChangePasswordPayload {
+                token,
+                old_password: p.old_password,
+                new_password: p.new_password,
+            };
+
+            auth.change_password(p)
+                .map(|_| {
+                    info!("User changed password successfully");
+                    Json(PasswordSuccess::PasswordChanged)
+                }).map_err(|e| {
+                    error!("Unable to 'change_password': {:?}", e);
+                    e.into()
+                })
+        }
+        RequestPasswordReset(p) => {
+            let p = RequestPasswordResetPayload { email: p.email };
+
+            match connect_to_auth()?.request_password_reset(p) {
+                Ok(reset) => {
+                    let mail = Mail {
+                        to: reset.email,
+                        subject: "Password reset".to_string(),
+                        body: format!(
+                            "Hi {},\n\nSomeone requested a password reset for your account. \
+                             Use the following token to choose a new password:\n\n{}\n\n\
+                             If this was not you, you can ignore this mail.",
+                            reset.username,
+                            reset.reset_token.as_ref()
+                        ),
+                    };
+                    // Failing here would reveal that the email belongs to a user
+                    match mailer.send(&mail) {
+                        Ok(()) => info!("Password reset mail sent to user '{}'", reset.username),
+                        Err(e) => error!("Unable to send password reset mail: {}", e),
+                    }
+                }
+                // Do not reveal whether the email belongs to a user
+                Err(e) => info!("Password reset not issued: {:?}", e),
+            }
+            Ok(Json(PasswordSuccess::PasswordResetRequested))
+        }
+        ResetPassword(p) => {
+            let auth = connect_to_auth()?;
+            let account = auth.get_reset_account(p.reset_token.clone()).map_err(|e| {
+                info!("Password reset with an unusable token: {:?}", e);
+                e
+            })?;
+            password_policy::verify(
+                p.new_password.as_ref(),
+                Some(account.username.as_str()),
+                Some(account.email.as_str()),
+            )?;
+
+            auth.reset_password(p)
+                .map(|_| {
+                    info!("User reset password successfully");
+                    Json(PasswordSuccess::PasswordReset)
+                }).map_err(|e| {
+                    error!("Unable to 'reset_password': {:?}", e);
+                    e.into()
+                })
+        }
+    }
+}
+
+/// The logged in user together with its profile
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MePayload {
+    pub role: Role,
+    #[serde(flatten)]
+    pub user: UserPayload,
+}
+
+/// Get the logged in user
+///
+/// The session cookie cannot be read by the frontend, so this is how it
+/// learns who it is logged in as. Responds with `UNAUTHENTICATED` if the
+/// request has no valid session or API key.
+///
+/// # Example
+///
+/// ## Query
+///
+/// ´´´text
+/// localhost:9234/api/me
+/// ´´´
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "role": "USER",
+///     "id": 22,
+///     "username": "FT45",
+///     "description": "Hello Everyone. I like programming",
+///     "avatar": "pictures/FT45.png"
+/// }
+/// ´´´
+#[get("/me")]
+pub fn me(user: AuthenticatedUser) -> ApiResult<MePayload> {
+    connect_to_controller()?
+        .get_user(GetUserPayload { id: user.id })
+        .map(|v| {
+            info!("Returning success from 'me' request");
+            Json(MePayload {
+                role: user.role,
+                user: v,
+            })
+        }).map_err(|e| {
+            error!("Unable to 'get-user': {:?}", e);
+            e.into()
+        })
+}
+
+/// Credentials of the admin account created by `create_admin`
+struct AdminCredentials {
+    username: String,
+    email: String,
+    password: String,
+}
+
+impl AdminCredentials {
+    /// Read the credentials without user interaction
+    ///
+    /// If `ADMIN_SECRETS_FILE` is set the credentials are read from that file,
+    /// otherwise from `ADMIN_USERNAME`, `ADMIN_EMAIL` and `ADMIN_PASSWORD`.
+    /// Returns `None` if none of them are set.
+    fn from_env() -> Result<Option<AdminCredentials>, String> {
+        if let Ok(path) = std::env::var("ADMIN_SECRETS_FILE") {
+            return AdminCredentials::from_file(&path).map(Some);
+        }
+
+        match (
+            std::env::var("ADMIN_USERNAME"),
+            std::env::var("ADMIN_EMAIL"),
+            std::env::var("ADMIN_PASSWORD"),
+        ) {
+            (Ok(username), Ok(email), Ok(password)) => Ok(Some(AdminCredentials {
+                username,
+                email,
+                password,
+            })),
+            (Err(_), Err(_), Err(_)) => Ok(None),
+            _ => Err("ADMIN_USERNAME, ADMIN_EMAIL and ADMIN_PASSWORD must all be set".to_string()),
+        }
+    }
+
+    /// Read the credentials from a secrets file
+    ///
+    /// The file contains one `key=value` pair per line with the keys
+    /// `username`, `email` and `password`. Empty lines and lines starting with
+    /// `#` are ignored.
+    fn from_file(path: &str) -> Result<AdminCredentials, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read secrets file '{}': {}", path, e))?;
+
+        let mut username = None;
+        let mut email = None;
+        let mut password = None;
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().map(|v| v.trim().to_string());
+            match key {
+                "username" => username = value,
+                "email" => email = value,
+                "password" => password = value,
+                _ => warn!("Ignoring unknown key '{}' in secrets file", key),
+            }
+        }
+
+        match (username, email, password) {
+            (Some(username), Some(email), Some(password)) => Ok(AdminCredentials {
+                username,
+                email,
+                password,
+            }),
+            _ => Err(format!(
+                "Secrets file '{}' must contain 'username', 'email' and 'password'",
+                path
+            )),
+        }
+    }
+
+    /// Validate the credentials
+    fn into_payload(self) -> Result<RegisterUserPayload, String> {
+        Ok(RegisterUserPayload {
+            username: self
+                .username
+                .try_into()
+                .map_err(|_| "Invalid username".to_string())?,
+            email: self
+                .email
+                .try_into()
+                .map_err(|_| "Invalid email".to_string())?,
+            password: self
+                .password
+                .try_into()
+                .map_err(|_| "Invalid password".to_string())?,
+        })
+    }
+}
+
+/// Ask for the admin credentials on the terminal
+fn prompt_admin_payload() -> Result<RegisterUserPayload, String> {
     let username;
     let email;
-    let password;
 
     println!("Username: ");
     loop {
         let mut string = String::new();
-        io::stdin().read_line(&mut string).unwrap();
+        io::stdin()
+            .read_line(&mut string)
+            .map_err(|e| format!("Unable to read username: {}", e))?;
 
         match string.trim().to_string().try_into() {
             Ok(value) => {
@@ -176,7 +627,9 @@ pub fn create_admin() {
     println!("Email: ");
     loop {
         let mut string = String::new();
-        io::stdin().read_line(&mut string).unwrap();
+        io::stdin()
+            .read_line(&mut string)
+            .map_err(|e| format!("Unable to read email: {}", e))?;
 
         match string.trim().to_string().try_into() {
             Ok(value) => {
@@ -187,36 +640,136 @@ pub fn create_admin() {
         };
     }
 
+    Ok(RegisterUserPayload {
+        username,
+        email,
+        password: prompt_password()?,
+    })
+}
+
+/// Ask for a password on the terminal without echoing it
+pub fn prompt_password() -> Result<PlainPassword, String> {
     loop {
-        let string = rpassword::prompt_password_stdout("Password: ").unwrap();
+        let string = rpassword::prompt_password_stdout("Password: ")
+            .map_err(|e| format!("Unable to read password: {}", e))?;
 
         match string.trim().to_string().try_into() {
-            Ok(value) => {
-                password = value;
-                break;
-            }
+            Ok(value) => return Ok(value),
             Err(_) => println!("Invalid password"),
         };
     }
+}
 
-    let p = RegisterUserPayload {
-        username,
-        email,
-        password,
-    };
+/// Check the password of a new account against the password policy
+fn check_password_policy(p: &RegisterUserPayload) -> Result<(), String> {
+    password_policy::check(
+        p.password.as_ref(),
+        Some(p.username.as_ref()),
+        Some(p.email.as_ref()),
+    ).map_err(|rejection| format!("Password rejected: {:?}", rejection))
+}
+
+/// Create a user in both services and give it `role`
+pub fn create_user(p: RegisterUserPayload, role: Role) -> Result<UserId, String> {
+    check_password_policy(&p)?;
+
+    let auth = connect_to_auth()
+        .map_err(|e| format!("Unable to connect to the auth service: {:?}", e))?;
 
     let user = auth
         .register(p)
-        .expect("Failed to register user in the auth service");
-    let user = controller
-        .add_user(user)
-        .expect("Failed to register user in the controller");
+        .map_err(|e| format!("Failed to register user in the auth service: {:?}", e))?;
+    let user = users::provision(&auth, user)
+        .map_err(|e| format!("Failed to register user in the controller: {:?}", e))?;
+    println!("Account created successfully\n{:#?}", user);
+
+    if role != Role::User {
+        if let Err(e) = auth.set_user_role(SetUserRolePayload { id: user.id, role }) {
+            // Do not leave a user with a role nobody asked for behind
+            return Err(match users::remove(&auth, user.id) {
+                Ok(()) => format!("Failed to set user role, removed the user again: {:?}", e),
+                Err(rollback) => format!(
+                    "Failed to set user role ({:?}) and to remove the user again ({}), user \
+                     ({:?}) is left with the default role",
+                    e, rollback, user.id
+                ),
+            });
+        }
+        info!(
+            target: "audit",
+            "Role change: command line created user ({:?}) with role {:?}",
+            user.id, role
+        );
+    }
+    Ok(user.id)
+}
+
+/// Create an admin account, or promote it if it already exists
+///
+/// The credentials are taken from the environment (see
+/// `AdminCredentials::from_env`) and only asked for interactively if none are
+/// set. Running this several times with the same credentials is harmless.
+pub fn create_admin() -> Result<(), String> {
+    println!("Creating admin account");
+
+    let p = match AdminCredentials::from_env()? {
+        Some(credentials) => credentials.into_payload()?,
+        None => prompt_admin_payload()?,
+    };
+
+    let auth = connect_to_auth()
+        .map_err(|e| format!("Unable to connect to the auth service: {:?}", e))?;
+
+    // The account may exist from an earlier run, maybe with another password.
+    // Then we only have to make sure that it is an admin. Its password was
+    // set before, so the policy is not checked.
+    let username = {
+        let username: &str = p.username.as_ref();
+        username.to_string()
+    };
+    let existing = auth
+        .find_user(username.clone())
+        .map_err(|e| format!("Unable to look up the admin account: {:?}", e))?;
+    let id = match existing {
+        Some(id) => {
+            let role = auth
+                .get_user_role(id)
+                .map_err(|e| format!("Failed to get existing user: {:?}", e))?;
+
+            if role >= Role::Admin {
+                println!("Account already exists and is an admin");
+                return Ok(());
+            }
+            println!("Account already exists, promoting it to admin");
+            id
+        }
+        None => {
+            info!("No account '{}' yet, registering it", username);
+            check_password_policy(&p)?;
+
+            let user = auth
+                .register(p)
+                .map_err(|e| format!("Failed to register user in the auth service: {:?}", e))?;
+            let user = users::provision(&auth, user)
+                .map_err(|e| format!("Failed to register user in the controller: {:?}", e))?;
+            println!("Account created successfully\n{:#?}", user);
+            user.id
+        }
+    };
 
     let p = SetUserRolePayload {
-        id: user.id,
+        id,
         role: Role::Admin,
     };
-    auth.set_user_role(p).expect("Failed to set user role");
+    auth.set_user_role(p)
+        .map_err(|e| format!("Failed to set user role: {:?}", e))?;
+    info!(
+        target: "audit",
+        "Role change: command line promoted user ({:?}) to {:?}",
+        id,
+        Role::Admin
+    );
 
-    println!("Account created successfully\n{:#?}", user);
+    println!("Account is now an admin");
+    Ok(())
 }
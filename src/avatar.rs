@@ -0,0 +1,443 @@
+//! Upload of avatar images.
+//!
+//! Avatars are sent as `multipart/form-data` with the image in the `avatar`
+//! field. The format is detected from the content, not from the declared
+//! content type, and only PNG, JPEG and GIF images are accepted. Uploads are
+//! limited to `AVATAR_MAX_BYTES` (default 1 MiB) and `AVATAR_MAX_DIMENSION`
+//! (default 1024) pixels in width and height.
+//!
+//! Accepted images are decoded and encoded again as PNG, which drops any
+//! metadata like EXIF location data. They are stored in `AVATAR_DIR` (default
+//! `avatars`), named by the SHA-256 digest of the stored image, and served
+//! from `/avatars/<name>`. The previous avatar of a user is removed once no
+//! other user has the same image.
+//!
+//! A user may upload one avatar every `AVATAR_UPLOAD_INTERVAL` seconds
+//! (default 60).
+use chrono::prelude::*;
+use chrono::Duration;
+use rocket::http::ContentType;
+use rocket::response::NamedFile;
+use rocket::Data;
+use rocket_contrib::Json;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use image::{ImageFormat, ImageOutputFormat};
+use multipart::server::Multipart;
+
+use datatypes::content::requests::{EditUserPayload, GetUserPayload};
+use datatypes::content::responses::ContentSuccess;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::UserId;
+
+use crate::comms::controller::SyncClient as ControllerClient;
+use crate::content::connect_to_controller;
+use crate::crypto;
+use crate::error::{ApiError, ApiResult, GateError};
+use crate::guards::AuthenticatedUser;
+use crate::policy::{self, Action};
+
+lazy_static! {
+    static ref MAX_BYTES: u64 = env_number("AVATAR_MAX_BYTES", 1024 * 1024) as u64;
+    static ref MAX_DIMENSION: u32 = env_number("AVATAR_MAX_DIMENSION", 1024);
+    static ref UPLOAD_INTERVAL: Duration =
+        Duration::seconds(i64::from(env_number("AVATAR_UPLOAD_INTERVAL", 60)));
+    static ref AVATAR_DIR: PathBuf = match std::env::var("AVATAR_DIR") {
+        Ok(value) => PathBuf::from(value),
+        Err(_) => {
+            warn!("AVATAR_DIR is not set, using 'avatars'");
+            PathBuf::from("avatars")
+        }
+    };
+    /// When each user last uploaded an avatar
+    static ref LAST_UPLOADS: Mutex<HashMap<UserId, DateTime<Utc>>> = Mutex::new(HashMap::new());
+    /// Held while avatars are stored or removed, so a file is not removed
+    /// while another user switches to it
+    static ref STORING: Mutex<()> = Mutex::new(());
+}
+
+/// Slack for the multipart boundaries and headers around the image
+const MULTIPART_OVERHEAD: u64 = 16 * 1024;
+
+fn env_number(name: &str, default: u32) -> u32 {
+    match std::env::var(name).map(|value| value.parse::<u32>()) {
+        Ok(Ok(value)) => value,
+        Ok(Err(_)) => {
+            warn!("{} is not a number, using '{}'", name, default);
+            default
+        }
+        Err(_) => {
+            warn!("{} is not set, using '{}'", name, default);
+            default
+        }
+    }
+}
+
+/// Why an avatar was rejected
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AvatarRejection {
+    /// The request has no `avatar` field
+    Missing,
+    TooLarge { max_bytes: u64 },
+    TooManyPixels { max_dimension: u32 },
+    /// The image is not a PNG, JPEG or GIF
+    UnsupportedFormat,
+    /// The image could not be decoded
+    Malformed,
+    /// The user uploaded an avatar too recently, try again in `retry_after`
+    /// seconds
+    TooManyUploads { retry_after: i64 },
+}
+
+fn rejected(reason: AvatarRejection) -> GateError {
+    info!("Rejected avatar: {:?}", reason);
+    GateError::AvatarRejected(reason)
+}
+
+/// Detect the format of an image from its magic bytes
+fn sniff(bytes: &[u8]) -> Option<ImageFormat> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::PNG)
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::JPEG)
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some(ImageFormat::GIF)
+    } else {
+        None
+    }
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from(*bytes.get(at)?) << 8 | u32::from(*bytes.get(at + 1)?))
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(be16(bytes, at)? << 16 | be16(bytes, at + 2)?)
+}
+
+/// Read width and height from the header of an image
+///
+/// This happens before decoding, so huge images are rejected without
+/// allocating memory for their pixels.
+fn dimensions(bytes: &[u8], format: ImageFormat) -> Option<(u32, u32)> {
+    match format {
+        // The IHDR chunk always comes first
+        ImageFormat::PNG => Some((be32(bytes, 16)?, be32(bytes, 20)?)),
+        // The logical screen descriptor follows the signature
+        ImageFormat::GIF => Some((
+            u32::from(*bytes.get(6)?) | u32::from(*bytes.get(7)?) << 8,
+            u32::from(*bytes.get(8)?) | u32::from(*bytes.get(9)?) << 8,
+        )),
+        // Skip segments until a start of frame
+        ImageFormat::JPEG => {
+            let mut at = 2;
+            loop {
+                if *bytes.get(at)? != 0xFF {
+                    return None;
+                }
+                let marker = *bytes.get(at + 1)?;
+                match marker {
+                    0xFF => at += 1,
+                    0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                        return Some((be16(bytes, at + 7)?, be16(bytes, at + 5)?));
+                    }
+                    _ => at += 2 + be16(bytes, at + 2)? as usize,
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Check and re-encode an uploaded image as PNG
+fn process(bytes: &[u8]) -> Result<Vec<u8>, GateError> {
+    let format = sniff(bytes).ok_or_else(|| rejected(AvatarRejection::UnsupportedFormat))?;
+    let (width, height) =
+        dimensions(bytes, format).ok_or_else(|| rejected(AvatarRejection::Malformed))?;
+    if width == 0 || height == 0 {
+        return Err(rejected(AvatarRejection::Malformed));
+    }
+    if width > *MAX_DIMENSION || height > *MAX_DIMENSION {
+        return Err(rejected(AvatarRejection::TooManyPixels {
+            max_dimension: *MAX_DIMENSION,
+        }));
+    }
+
+    let image = image::load_from_memory_with_format(bytes, format)
+        .map_err(|_| rejected(AvatarRejection::Malformed))?;
+    let mut png = Vec::new();
+    image
+        .write_to(&mut png, ImageOutputFormat::PNG)
+        .map_err(|e| {
+            error!("Unable to encode avatar: {}", e);
+            rejected(AvatarRejection::Malformed)
+        })?;
+    Ok(png)
+}
+
+/// Read the `avatar` field of a multipart body
+fn read_avatar(content_type: &ContentType, data: Data) -> Result<Vec<u8>, GateError> {
+    let boundary = content_type
+        .params()
+        .find(|&(key, _)| key == "boundary")
+        .map(|(_, value)| value)
+        .ok_or_else(|| rejected(AvatarRejection::Missing))?;
+
+    let body = data.open().take(*MAX_BYTES + MULTIPART_OVERHEAD);
+    let mut multipart = Multipart::with_body(body, boundary);
+    let malformed = |e: io::Error| {
+        info!("Unable to read multipart body: {}", e);
+        rejected(AvatarRejection::Malformed)
+    };
+
+    while let Some(mut field) = multipart.read_entry().map_err(malformed)? {
+        if &*field.headers.name != "avatar" {
+            continue;
+        }
+
+        let mut bytes = Vec::new();
+        field
+            .data
+            .by_ref()
+            .take(*MAX_BYTES + 1)
+            .read_to_end(&mut bytes)
+            .map_err(malformed)?;
+        if bytes.len() as u64 > *MAX_BYTES {
+            return Err(rejected(AvatarRejection::TooLarge {
+                max_bytes: *MAX_BYTES,
+            }));
+        }
+        return Ok(bytes);
+    }
+    Err(rejected(AvatarRejection::Missing))
+}
+
+/// Fail if `id` uploaded an avatar less than `UPLOAD_INTERVAL` ago, and
+/// count this upload otherwise
+fn check_rate(id: UserId) -> Result<(), ApiError> {
+    let now = Utc::now();
+    let mut uploads = LAST_UPLOADS.lock().map_err(|e| {
+        error!("Unable to lock avatar uploads: {}", e);
+        ResponseError::InternalServerError
+    })?;
+    uploads.retain(|_, last| *last + *UPLOAD_INTERVAL > now);
+
+    if let Some(last) = uploads.get(&id) {
+        let retry_after = (*last + *UPLOAD_INTERVAL - now).num_seconds() + 1;
+        return Err(rejected(AvatarRejection::TooManyUploads { retry_after }).into());
+    }
+    uploads.insert(id, now);
+    Ok(())
+}
+
+/// Whether `name` is a file name handed out by `store`
+fn is_stored_name(name: &str) -> bool {
+    name.len() == 68
+        && name.ends_with(".png")
+        && name.as_bytes()[..64].iter().all(u8::is_ascii_hexdigit)
+}
+
+/// Store an avatar under its digest, returning the file name
+fn store(png: &[u8]) -> Result<String, ResponseError> {
+    let name = format!("{}.png", crypto::sha256_hex(png));
+    let path = AVATAR_DIR.join(&name);
+    if path.exists() {
+        return Ok(name);
+    }
+
+    // Write to a temporary file first, so a half written avatar is never served
+    let tmp = AVATAR_DIR.join(format!(".{}.tmp", crypto::random_hex(8)));
+    std::fs::create_dir_all(&*AVATAR_DIR)
+        .and_then(|_| std::fs::write(&tmp, png))
+        .and_then(|_| std::fs::rename(&tmp, &path))
+        .map_err(|e| {
+            error!("Unable to store avatar '{}': {}", path.display(), e);
+            let _ = std::fs::remove_file(&tmp);
+            ResponseError::InternalServerError
+        })?;
+    Ok(name)
+}
+
+/// Remove the stored avatar `old` unless a user still has it
+///
+/// Avatars are named by their digest, so users with the same image share one
+/// file. Avatars which were not stored by `store` are left alone.
+fn remove_unused(controller: &ControllerClient, old: &str) {
+    let name = if old.starts_with("avatars/") {
+        &old["avatars/".len()..]
+    } else {
+        return;
+    };
+    if !is_stored_name(name) {
+        return;
+    }
+
+    match controller.get_all_users(()) {
+        Ok(users) => {
+            if users.iter().any(|u| u.avatar.as_ref().map(String::as_str) == Some(old)) {
+                return;
+            }
+        }
+        Err(e) => {
+            error!("Unable to 'get-all-users' to remove avatar '{}': {:?}", name, e);
+            return;
+        }
+    }
+    match std::fs::remove_file(AVATAR_DIR.join(name)) {
+        Ok(_) => info!("Removed unused avatar '{}'", name),
+        Err(e) => error!("Unable to remove unused avatar '{}': {}", name, e),
+    }
+}
+
+/// Upload a new avatar for the logged in user
+///
+/// # Example
+///
+/// ´´´text
+/// POST localhost:9234/api/user/avatar
+/// Content-Type: multipart/form-data; boundary=XYZ
+///
+/// --XYZ
+/// Content-Disposition: form-data; name="avatar"; filename="me.jpg"
+/// Content-Type: image/jpeg
+///
+/// <image>
+/// --XYZ--
+/// ´´´
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "type": "USER",
+///     "payload": {
+///         "id": 22,
+///         "username": "FT45",
+///         "description": "Hello Everyone. I like programming",
+///         "avatar": "avatars/3f2a...9c.png"
+///     }
+/// }
+/// ´´´
+///
+/// Uploading again within `AVATAR_UPLOAD_INTERVAL` seconds gives:
+///
+/// ´´´json
+/// {
+///     "type": "AVATAR_REJECTED",
+///     "payload": {
+///         "type": "TOO_MANY_UPLOADS",
+///         "payload": { "retry_after": 42 }
+///     }
+/// }
+/// ´´´
+#[post("/user/avatar", format = "multipart/form-data", data = "<data>")]
+pub fn post_avatar(
+    user: AuthenticatedUser,
+    content_type: &ContentType,
+    data: Data,
+) -> ApiResult<ContentSuccess> {
+    policy::authorize(Action::EditUser, &user, None)?;
+    check_rate(user.id)?;
+
+    let png = process(&read_avatar(content_type, data)?)?;
+
+    let controller = connect_to_controller()?;
+    let old = controller
+        .get_user(GetUserPayload { id: user.id })
+        .map_err(|e| {
+            error!("Unable to 'get-user' to replace avatar: {:?}", e);
+            ResponseError::from(e)
+        })?.avatar;
+
+    let _storing = STORING.lock().map_err(|e| {
+        error!("Unable to lock avatar storage: {}", e);
+        ResponseError::InternalServerError
+    })?;
+    let name = store(&png)?;
+    let avatar = format!("avatars/{}", name);
+
+    let payload = EditUserPayload {
+        id: Some(user.id),
+        description: None,
+        avatar: Some(avatar.clone()),
+    };
+    let v = controller.edit_user(payload).map_err(|e| {
+        error!("Unable to 'edit-user' with new avatar: {:?}", e);
+        ResponseError::from(e)
+    })?;
+    info!("User ({:?}) uploaded avatar '{}'", user.id, name);
+
+    if let Some(old) = old.filter(|old| *old != avatar) {
+        remove_unused(&controller, &old);
+    }
+    info!("Returning success from 'upload-avatar' request");
+    Ok(Json(ContentSuccess::User(v)))
+}
+
+/// Get a stored avatar
+#[get("/avatars/<name>")]
+pub fn get_avatar(name: String) -> Option<NamedFile> {
+    // Only names handed out by `store` are served
+    if !is_stored_name(&name) {
+        return None;
+    }
+    NamedFile::open(Path::new(&*AVATAR_DIR).join(name)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\x01\x2c\0\0\0\xc8";
+
+    #[test]
+    fn sniff_detects_formats() {
+        assert_eq!(sniff(PNG_HEADER), Some(ImageFormat::PNG));
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some(ImageFormat::JPEG));
+        assert_eq!(sniff(b"GIF87a"), Some(ImageFormat::GIF));
+        assert_eq!(sniff(b"GIF89a"), Some(ImageFormat::GIF));
+    }
+
+    #[test]
+    fn sniff_rejects_other_content() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"), None);
+        assert_eq!(sniff(b"BM\0\0"), None);
+        assert_eq!(sniff(b"\x89PNG"), None);
+    }
+
+    #[test]
+    fn png_dimensions() {
+        assert_eq!(dimensions(PNG_HEADER, ImageFormat::PNG), Some((300, 200)));
+        assert_eq!(dimensions(&PNG_HEADER[..20], ImageFormat::PNG), None);
+    }
+
+    #[test]
+    fn gif_dimensions() {
+        let gif = b"GIF89a\x2c\x01\xc8\x00";
+        assert_eq!(dimensions(gif, ImageFormat::GIF), Some((300, 200)));
+        assert_eq!(dimensions(&gif[..8], ImageFormat::GIF), None);
+    }
+
+    #[test]
+    fn jpeg_dimensions_after_other_segments() {
+        let jpeg = [
+            0xFF, 0xD8, // Start of image
+            0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, // APP0 with two bytes of data
+            0xFF, 0xC4, 0x00, 0x02, // Huffman table, not a start of frame
+            0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0xC8, 0x01, 0x2C, // Start of frame
+        ];
+        assert_eq!(dimensions(&jpeg, ImageFormat::JPEG), Some((300, 200)));
+    }
+
+    #[test]
+    fn jpeg_dimensions_of_truncated_image() {
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+        assert_eq!(dimensions(&jpeg, ImageFormat::JPEG), None);
+        assert_eq!(dimensions(&[0xFF, 0xD8, 0x00], ImageFormat::JPEG), None);
+    }
+}
@@ -1,9 +1,15 @@
 //! The code to ban, unban and check if ip is banned.
+//!
+//! If `BANNED_IPS_FILE` is set, bans are loaded from that file on startup and
+//! written back whenever an admin bans or unbans an address. The file contains
+//! one address per line. It is read again before every write, so bans changed
+//! with the `ban` command while the gate is running are not lost.
 
 use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{self, FromRequest};
 use rocket::Rocket;
 use rocket::State;
-use rocket::{Data, Request};
+use rocket::{Data, Outcome, Request};
 use rocket_contrib::Json;
 use std::collections::HashSet;
 use std::net::IpAddr;
@@ -16,16 +22,72 @@ use std::collections::HashMap;
 
 use datatypes::admin::requests::AdminRequest;
 use datatypes::admin::responses::AdminSuccess;
-use datatypes::auth::responses::*;
+use datatypes::auth::requests::SetUserRolePayload;
+use datatypes::auth::responses::Role;
 use datatypes::content::responses::*;
 use datatypes::error::ResponseError;
-use datatypes::valid::token::Token;
+use datatypes::valid::ids::UserId;
 
 use crate::auth::connect_to_auth;
-use crate::JsonResponseResult;
+use crate::comms::auth::SyncClient as AuthClient;
+use crate::comms::auth::{AdminGuardedChange, AdminGuardedOutcome, SuspendUserPayload};
+use crate::error::{ApiError, ApiResult, GateError, LegacyResult};
+use crate::guards::AuthenticatedUser;
+use crate::policy::{self, Action};
+use crate::sudo;
+use crate::token_cache::TOKEN_CACHE;
 
 const REQUEST_LIMIT: u32 = 40;
 
+lazy_static! {
+    static ref BANNED_IPS_FILE: Option<String> = match std::env::var("BANNED_IPS_FILE") {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("BANNED_IPS_FILE is not set, bans are kept in memory only");
+            None
+        }
+    };
+}
+
+/// Read the banned addresses from `BANNED_IPS_FILE`
+///
+/// A missing file means that nobody is banned.
+pub fn load_bans() -> Result<HashSet<IpAddr>, String> {
+    let path = match &*BANNED_IPS_FILE {
+        Some(path) => path,
+        None => return Ok(HashSet::new()),
+    };
+
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(format!("Unable to read '{}': {}", path, e)),
+    };
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse::<IpAddr>()
+                .map_err(|_| format!("Invalid address '{}' in '{}'", line, path))
+        }).collect()
+}
+
+/// Write the banned addresses to `BANNED_IPS_FILE`
+pub fn save_bans(banned_ips: &HashSet<IpAddr>) -> Result<(), String> {
+    let path = BANNED_IPS_FILE
+        .as_ref()
+        .ok_or_else(|| "BANNED_IPS_FILE is not set".to_string())?;
+
+    let mut ips: Vec<String> = banned_ips.iter().map(IpAddr::to_string).collect();
+    ips.sort();
+    let mut content = ips.join("\n");
+    content.push('\n');
+
+    std::fs::write(path, content).map_err(|e| format!("Unable to write '{}': {}", path, e))
+}
+
 pub struct Count {
     pub count: u32,
     pub time: DateTime<Utc>,
@@ -53,6 +115,50 @@ pub struct BanIpAddrs {
     pub map: Arc<RwLock<HashMap<IpAddr, Count>>>,
 }
 
+impl BanIpAddrs {
+    /// Start with the bans stored in `BANNED_IPS_FILE`
+    pub fn load() -> BanIpAddrs {
+        let banned_ips = load_bans().unwrap_or_else(|e| {
+            error!("Unable to load banned ips: {}", e);
+            HashSet::new()
+        });
+        info!("Loaded {} banned ips", banned_ips.len());
+
+        BanIpAddrs {
+            banned_ips: Arc::new(RwLock::new(banned_ips)),
+            map: Default::default(),
+        }
+    }
+}
+
+/// Persist a ban or unban by an admin, if `BANNED_IPS_FILE` is set
+///
+/// Only this change is applied to what is in the file now, and bans added to
+/// the file in the meantime are picked up by `banned_ips`.
+fn persist_ban(banned_ips: &mut HashSet<IpAddr>, ip: IpAddr, banned: bool) {
+    if BANNED_IPS_FILE.is_none() {
+        return;
+    }
+
+    let mut stored = match load_bans() {
+        Ok(stored) => stored,
+        Err(e) => {
+            error!("Unable to save banned ips: {}", e);
+            return;
+        }
+    };
+    if banned {
+        stored.insert(ip);
+    } else {
+        stored.remove(&ip);
+    }
+    banned_ips.extend(stored.iter().cloned());
+
+    if let Err(e) = save_bans(&stored) {
+        error!("Unable to save banned ips: {}", e);
+    }
+}
+
 // Be sure blacklist is added "globally" (on_attach) and is checked on every response (on_request).
 impl Fairing for BanIpAddrs {
     fn info(&self) -> Info {
@@ -154,6 +260,104 @@ impl Fairing for BanIpAddrs {
     }
 }
 
+/// Sent as `X-Confirm-Self-Demotion: true` by admins lowering their own role
+pub struct ConfirmSelfDemotion;
+
+impl<'a, 'r> FromRequest<'a, 'r> for ConfirmSelfDemotion {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<ConfirmSelfDemotion, ()> {
+        match req.headers().get_one("X-Confirm-Self-Demotion") {
+            Some("true") => Outcome::Success(ConfirmSelfDemotion),
+            _ => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Who changed a user, for the audit log
+#[derive(Debug, Clone, Copy)]
+pub enum Actor {
+    Admin(UserId),
+    CommandLine,
+}
+
+impl std::fmt::Display for Actor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Actor::Admin(id) => write!(f, "admin ({:?})", id),
+            Actor::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Change the role of a user unless that leaves the forum without an admin
+///
+/// Returns the previous role. Every change is written to the audit log.
+pub fn set_user_role(
+    auth: &AuthClient,
+    actor: Actor,
+    p: SetUserRolePayload,
+) -> Result<Role, ApiError> {
+    let user_id = p.id;
+    let new_role = p.role;
+    let outcome = auth
+        .apply_keeping_admin(AdminGuardedChange::SetUserRole(p))
+        .map_err(|e| {
+            error!("Error updating role: {:?}", e);
+            e
+        })?;
+
+    match outcome {
+        AdminGuardedOutcome::Applied { previous_role } => {
+            TOKEN_CACHE.invalidate_user(user_id);
+            info!(
+                target: "audit",
+                "Role change: {} changed role of user ({:?}) from {:?} to {:?}",
+                actor, user_id, previous_role, new_role
+            );
+            Ok(previous_role)
+        }
+        AdminGuardedOutcome::LastAdmin => {
+            warn!("{} tried to demote the last admin", actor);
+            Err(GateError::LastAdmin.into())
+        }
+    }
+}
+
+/// Suspend a user unless that leaves the forum without an admin
+///
+/// Every suspension is written to the audit log.
+pub fn suspend_user(
+    auth: &AuthClient,
+    actor: Actor,
+    p: SuspendUserPayload,
+) -> Result<(), ApiError> {
+    let user_id = p.id;
+    let until = p.until;
+    let outcome = auth
+        .apply_keeping_admin(AdminGuardedChange::SuspendUser(p))
+        .map_err(|e| {
+            error!("Error suspending user: {:?}", e);
+            e
+        })?;
+
+    match outcome {
+        AdminGuardedOutcome::Applied { previous_role } => {
+            TOKEN_CACHE.invalidate_user(user_id);
+            info!(
+                target: "audit",
+                "Suspension: {} suspended user ({:?}) with role {:?} until {:?}",
+                actor, user_id, previous_role, until
+            );
+            Ok(())
+        }
+        AdminGuardedOutcome::LastAdmin => {
+            warn!("{} tried to suspend the last admin", actor);
+            Err(GateError::LastAdmin.into())
+        }
+    }
+}
+
 /// Give banned message
 #[get("/banned")]
 fn banned_message() -> &'static str {
@@ -163,9 +367,20 @@ fn banned_message() -> &'static str {
 /// Ban or unban users.
 ///
 /// If you are admin, you can ban and unban users, and change user roles.
+/// Which roles may send which request is set by the policy, see `policy`.
 /// Request types: 'BAN_IP', 'UNBAN_IP', 'SET_USER_ROLE'.
 /// Return types: 'IP_BANNED', 'IP_UNBANNED' 'CHANGED_ROLE'.
 ///
+/// 'BAN_IP' and 'SET_USER_ROLE' need a recent confirmation of the credentials
+/// through 'api/auth/reauthenticate', otherwise the error
+/// 'REAUTHENTICATION_REQUIRED' is returned. Like all errors of this request it
+/// is sent with status 200.
+///
+/// Role changes which would leave no admin are refused with 'LAST_ADMIN'.
+/// Admins lowering their own role have to send the header
+/// 'X-Confirm-Self-Demotion: true', otherwise 'SELF_DEMOTION_NOT_CONFIRMED'
+/// is returned. Every role change is written to the audit log.
+///
 /// # Example
 ///
 /// Send this json to 'api/admin' (need to first log in as admin).
@@ -191,40 +406,42 @@ fn banned_message() -> &'static str {
 /// ´´´
 #[post("/admin", format = "application/json", data = "<req>")]
 pub fn post_admin(
-    token: Token,
+    user: AuthenticatedUser,
     req: Option<Json<AdminRequest>>,
     banned_ips: State<Arc<RwLock<HashSet<IpAddr>>>>,
-) -> JsonResponseResult<AdminSuccess> {
+    confirm: Option<ConfirmSelfDemotion>,
+) -> LegacyResult<AdminSuccess> {
+    handle_admin(user, req, &banned_ips, confirm.is_some()).map_err(Json)
+}
+
+fn handle_admin(
+    user: AuthenticatedUser,
+    req: Option<Json<AdminRequest>>,
+    banned_ips: &RwLock<HashSet<IpAddr>>,
+    confirm_self_demotion: bool,
+) -> ApiResult<AdminSuccess> {
     info!("post_admin");
-    let req = req
-        .ok_or(ContentError::InvalidContent)
-        .map_err(|e| Json(e.into()))?; // If invalid request give error.
-
-    // Check what role the user has (and that a user is valid):
-    info!("Checking token");
-    let (id, role) = connect_to_auth()
-        .map_err(Json)?
-        .get_user(token)
-        .map_err(|e| Json(e.into()))?;
-
-    info!("Id: {:?}, role: {:?}", id, role);
-    // Only admins can do something here (return with error if not admin)
-    if role < Role::Admin {
-        Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
-    }
+    let req = req.ok_or(ContentError::InvalidContent)?; // If invalid request give error.
+
+    info!("Id: {:?}, role: {:?}", user.id, user.role);
     use datatypes::admin::requests::AdminRequest::*;
     match req.into_inner() {
         BanIp(p) => {
+            policy::authorize(Action::BanIp, &user, None)?;
+            sudo::require(&user)?;
+
             // Use a separate scope to perform insertion
             //
             // This is to minimize the amount of time we store the
             // 'RwLockWriteGuard' to prevent blocking other requests from
             // reading from 'banned_ips'
             let res = {
-                banned_ips
+                let mut banned_ips = banned_ips
                     .write()
-                    .map_err(|_| Json(ResponseError::InternalServerError))?
-                    .insert(p.ip)
+                    .map_err(|_| ResponseError::InternalServerError)?;
+                let res = banned_ips.insert(p.ip);
+                persist_ban(&mut banned_ips, p.ip, true);
+                res
             };
 
             // true  => IpAddr is now banned
@@ -237,12 +454,16 @@ pub fn post_admin(
             Ok(AdminSuccess::IpBanned)
         }
         UnbanIp(p) => {
+            policy::authorize(Action::UnbanIp, &user, None)?;
+
             // Use a separate scope to perform removal
             let res = {
-                banned_ips
+                let mut banned_ips = banned_ips
                     .write()
-                    .map_err(|_| Json(ResponseError::InternalServerError))?
-                    .remove(&p.ip)
+                    .map_err(|_| ResponseError::InternalServerError)?;
+                let res = banned_ips.remove(&p.ip);
+                persist_ban(&mut banned_ips, p.ip, false);
+                res
             };
 
             // true  => IpAddr is now unbanned
@@ -255,14 +476,18 @@ pub fn post_admin(
             Ok(AdminSuccess::IpUnbanned)
         }
         SetUserRole(p) => {
-            connect_to_auth()
-                .map_err(Json)?
-                .set_user_role(p)
-                .map_err(|e| {
-                    error!("Error updating role: {:?}", e);
-                    Json(e.into())
-                })?;
+            policy::authorize(Action::SetUserRole, &user, None)?;
+            sudo::require(&user)?;
+
+            let auth = connect_to_auth()?;
+            if p.id == user.id && p.role < user.role && !confirm_self_demotion {
+                info!("Admin ({:?}) tried to demote themselves without confirming", user.id);
+                return Err(GateError::SelfDemotionNotConfirmed.into());
+            }
 
+            // The last admin check happens in the auth service, together with
+            // the change
+            set_user_role(&auth, Actor::Admin(user.id), p)?;
             debug!("Successfully updated role");
             Ok(AdminSuccess::ChangedRole)
         }
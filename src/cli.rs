@@ -0,0 +1,239 @@
+//! Command line interface of the gate.
+//!
+//! Without a subcommand (or with `serve`) the gate serves the API. The `user`
+//! and `ban` subcommands let operators manage the forum from a shell. They
+//! talk to the auth service and the controller directly, like the gate does.
+//! `policy` prints the effective permission policy.
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+use std::convert::TryInto;
+use std::net::IpAddr;
+
+use datatypes::auth::requests::{RegisterUserPayload, SetUserRolePayload};
+use datatypes::valid::ids::UserId;
+
+use crate::auth::{self, connect_to_auth};
+use crate::banned::{self, Actor};
+use crate::comms::auth::SuspendUserPayload;
+use crate::policy::{self, parse_role};
+use crate::users;
+
+/// Describe the command line arguments
+pub fn app() -> App<'static, 'static> {
+    App::new("security-gate")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .global(true)
+                .help("Increases logging verbosity each use for up to 3 times"),
+        ).arg(
+            Arg::with_name("admin")
+                .short("a")
+                .long("admin")
+                .multiple(true)
+                .help(
+                    "Create an admin account before serving, reading the credentials from \
+                     ADMIN_SECRETS_FILE or ADMIN_USERNAME, ADMIN_EMAIL and ADMIN_PASSWORD \
+                     if set, or asking for them otherwise",
+                ),
+        ).subcommand(SubCommand::with_name("serve").about("Serve the API (the default)"))
+        .subcommand(
+            SubCommand::with_name("user")
+                .about("Manage users")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create a user, asking for the password")
+                        .arg(
+                            Arg::with_name("username")
+                                .long("username")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("email")
+                                .long("email")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(role_arg().long("role").default_value("user")),
+                ).subcommand(
+                    SubCommand::with_name("set-role")
+                        .about("Change the role of a user")
+                        .arg(Arg::with_name("id").required(true))
+                        .arg(role_arg().required(true)),
+                ).subcommand(
+                    SubCommand::with_name("suspend")
+                        .about("Keep a user from logging in and end its sessions")
+                        .arg(Arg::with_name("id").required(true))
+                        .arg(
+                            Arg::with_name("until")
+                                .long("until")
+                                .takes_value(true)
+                                .help("Seconds since the unix epoch, indefinitely if not set"),
+                        ),
+                ).subcommand(
+                    SubCommand::with_name("reconcile")
+                        .about(
+                            "Find users which only exist in the auth service or only in the \
+                             controller, add the missing ones to the controller and remove \
+                             the ones without credentials",
+                        ).arg(
+                            Arg::with_name("dry-run")
+                                .long("dry-run")
+                                .help("Only report what would be repaired or removed"),
+                        ),
+                ),
+        ).subcommand(
+            SubCommand::with_name("ban")
+                .about(
+                    "Manage banned ip addresses in BANNED_IPS_FILE, changes take effect when \
+                     the gate is started",
+                ).setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Ban an ip address")
+                        .arg(Arg::with_name("ip").required(true)),
+                ).subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Unban an ip address")
+                        .arg(Arg::with_name("ip").required(true)),
+                ).subcommand(SubCommand::with_name("list").about("List banned ip addresses")),
+        ).subcommand(
+            SubCommand::with_name("policy")
+                .about("Print the effective permission policy, including POLICY_FILE"),
+        )
+}
+
+fn role_arg() -> Arg<'static, 'static> {
+    Arg::with_name("role")
+        .takes_value(true)
+        .possible_values(&["user", "moderator", "admin"])
+}
+
+fn parse_user_id(value: &str) -> Result<UserId, String> {
+    UserId::from_param(RawStr::from_str(value)).map_err(|_| format!("Invalid user id '{}'", value))
+}
+
+fn parse_ip(value: &str) -> Result<IpAddr, String> {
+    value
+        .parse::<IpAddr>()
+        .map_err(|_| format!("Invalid ip address '{}'", value))
+}
+
+/// Run a `user` subcommand
+pub fn user(matches: &ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        ("create", Some(m)) => {
+            let p = RegisterUserPayload {
+                username: m
+                    .value_of("username")
+                    .unwrap_or_default()
+                    .to_string()
+                    .try_into()
+                    .map_err(|_| "Invalid username".to_string())?,
+                email: m
+                    .value_of("email")
+                    .unwrap_or_default()
+                    .to_string()
+                    .try_into()
+                    .map_err(|_| "Invalid email".to_string())?,
+                password: auth::prompt_password()?,
+            };
+            let role = parse_role(m.value_of("role").unwrap_or("user"))?;
+
+            let id = auth::create_user(p, role)?;
+            println!("Created user ({:?}) with role {:?}", id, role);
+            Ok(())
+        }
+        ("set-role", Some(m)) => {
+            let id = parse_user_id(m.value_of("id").unwrap_or_default())?;
+            let role = parse_role(m.value_of("role").unwrap_or_default())?;
+
+            let auth = connect_to_auth()
+                .map_err(|e| format!("Unable to connect to the auth service: {:?}", e))?;
+            banned::set_user_role(&auth, Actor::CommandLine, SetUserRolePayload { id, role })
+                .map_err(|e| format!("Failed to set user role: {:?}", e))?;
+            println!("User ({:?}) is now {:?}", id, role);
+            Ok(())
+        }
+        ("suspend", Some(m)) => {
+            let id = parse_user_id(m.value_of("id").unwrap_or_default())?;
+            let until = match m.value_of("until") {
+                Some(value) => Some(
+                    value
+                        .parse::<i64>()
+                        .map_err(|_| format!("Invalid timestamp '{}'", value))?,
+                ),
+                None => None,
+            };
+
+            let auth = connect_to_auth()
+                .map_err(|e| format!("Unable to connect to the auth service: {:?}", e))?;
+            banned::suspend_user(&auth, Actor::CommandLine, SuspendUserPayload { id, until })
+                .map_err(|e| format!("Failed to suspend user: {:?}", e))?;
+            println!("User ({:?}) is suspended", id);
+            Ok(())
+        }
+        ("reconcile", Some(m)) => {
+            let report = users::reconcile(m.is_present("dry-run"))?;
+            println!("Repaired: {:?}", report.repaired);
+            println!("Removed from the controller: {:?}", report.removed);
+            println!("Failed: {:?}", report.failed);
+            if report.failed.is_empty() {
+                Ok(())
+            } else {
+                Err(format!("Unable to repair {} users", report.failed.len()))
+            }
+        }
+        _ => Err("Unknown user command".to_string()),
+    }
+}
+
+/// Run a `ban` subcommand
+pub fn ban(matches: &ArgMatches) -> Result<(), String> {
+    let mut banned_ips = banned::load_bans()?;
+
+    match matches.subcommand() {
+        ("add", Some(m)) => {
+            let ip = parse_ip(m.value_of("ip").unwrap_or_default())?;
+            if banned_ips.insert(ip) {
+                banned::save_bans(&banned_ips)?;
+                println!("Banned {}", ip);
+            } else {
+                println!("{} is already banned", ip);
+            }
+            Ok(())
+        }
+        ("remove", Some(m)) => {
+            let ip = parse_ip(m.value_of("ip").unwrap_or_default())?;
+            if banned_ips.remove(&ip) {
+                banned::save_bans(&banned_ips)?;
+                println!("Unbanned {}", ip);
+            } else {
+                println!("{} is not banned", ip);
+            }
+            Ok(())
+        }
+        ("list", _) => {
+            let mut ips: Vec<_> = banned_ips.into_iter().collect();
+            ips.sort();
+            for ip in ips {
+                println!("{}", ip);
+            }
+            Ok(())
+        }
+        _ => Err("Unknown ban command".to_string()),
+    }
+}
+
+/// Run the `policy` subcommand
+pub fn policy() -> Result<(), String> {
+    let rules = policy::rules()?;
+    println!("{}", policy::describe(rules));
+    Ok(())
+}
@@ -1,13 +1,300 @@
 use datatypes::auth::requests::*;
 use datatypes::auth::responses::*;
 use datatypes::content::requests::*;
-use datatypes::valid::ids::UserId;
+use datatypes::valid::fields::*;
+use datatypes::valid::ids::{CategoryId, UserId};
 use datatypes::valid::token::Token;
+use serde::de::{self, Deserialize, Deserializer};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+/// Change the password of the user owning `token`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChangePasswordPayload {
+    pub token: Token,
+    pub old_password: PlainPassword,
+    pub new_password: PlainPassword,
+}
+
+/// Ask the auth service to issue a single-use password reset token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RequestPasswordResetPayload {
+    pub email: Email,
+}
+
+/// A single-use password reset token
+///
+/// Reset tokens are issued by the auth service and only ever contain URL-safe
+/// characters, so anything else is rejected before it reaches the service.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResetToken(String);
+
+impl ResetToken {
+    pub const MAX_LENGTH: usize = 128;
+}
+
+impl TryFrom<String> for ResetToken {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<ResetToken, String> {
+        let valid = !value.is_empty()
+            && value.len() <= ResetToken::MAX_LENGTH
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if valid {
+            Ok(ResetToken(value))
+        } else {
+            Err("Invalid reset token".to_string())
+        }
+    }
+}
+
+impl AsRef<str> for ResetToken {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for ResetToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ResetToken, D::Error> {
+        ResetToken::try_from(String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// A freshly issued reset token together with where it should be delivered
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PasswordResetTokenPayload {
+    pub username: String,
+    pub email: String,
+    pub reset_token: ResetToken,
+}
+
+/// The name and email of an account, which new passwords must not contain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccountPayload {
+    pub username: String,
+    pub email: String,
+}
+
+/// Set a new password using a previously issued reset token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResetPasswordPayload {
+    pub reset_token: ResetToken,
+    pub new_password: PlainPassword,
+}
+
+/// Identifies a single session of a user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(pub u32);
+
+/// Where a session was created from, recorded right after authentication
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionContextPayload {
+    pub token: Token,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+/// An active session of a user
+///
+/// Timestamps are seconds since the unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionPayload {
+    pub id: SessionId,
+    pub created: i64,
+    pub last_seen: i64,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// Whether this is the session which made the request
+    pub current: bool,
+}
+
+/// Revoke the session `id` which must belong to the user owning `token`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RevokeSessionPayload {
+    pub token: Token,
+    pub id: SessionId,
+}
+
+/// The TOTP state of a user with two-factor authentication enabled
+///
+/// The secret is base32 encoded and the recovery codes are stored as SHA-256
+/// hex digests.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TotpPayload {
+    pub secret: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// Enable (`Some`) or disable (`None`) two-factor authentication for a user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SetTotpPayload {
+    pub id: UserId,
+    pub totp: Option<TotpPayload>,
+}
+
+/// Identifies a single API key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ApiKeyId(pub u32);
+
+/// What an API key may be used for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ApiScope {
+    /// Read content, including hidden content if the owner may see it
+    ReadOnly,
+    /// Add, edit and hide the threads of the owner
+    PostThreads,
+    /// Add, edit and hide the comments of the owner
+    PostComments,
+    /// Moderate a single category, if the owner is a moderator
+    ModerateCategory(CategoryId),
+}
+
+/// An API key, without the key itself
+///
+/// Timestamps are seconds since the unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyPayload {
+    pub id: ApiKeyId,
+    pub user_id: UserId,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created: i64,
+    pub expires: Option<i64>,
+}
+
+/// Store a new API key, identified by the SHA-256 hex digest of the key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreateApiKeyPayload {
+    pub user_id: UserId,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub expires: Option<i64>,
+    pub key_hash: String,
+}
+
+/// Revoke the API key `id` of the user `user_id`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RevokeApiKeyPayload {
+    pub user_id: UserId,
+    pub id: ApiKeyId,
+}
+
+/// An invite code which allows registering while registration is invite-only
+///
+/// Timestamps are seconds since the unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InvitePayload {
+    pub code: String,
+    pub created_by: UserId,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub max_uses: u32,
+    /// The users who registered with this invite
+    pub used_by: Vec<UserId>,
+}
+
+/// Store a new invite code
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreateInvitePayload {
+    pub code: String,
+    pub created_by: UserId,
+    pub expires: Option<i64>,
+    pub max_uses: u32,
+}
+
+/// Record that `user_id` registered with the invite use held by
+/// `reservation`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedeemInvitePayload {
+    pub reservation: String,
+    pub user_id: UserId,
+}
+
+/// Keep a user from logging in until `until` (seconds since the unix epoch),
+/// or indefinitely if `until` is not set
+///
+/// Suspending a user ends all of its sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SuspendUserPayload {
+    pub id: UserId,
+    pub until: Option<i64>,
+}
+
+/// A change of a user which must not leave the forum without an admin
+///
+/// The auth service checks that an admin who is not suspended remains and
+/// applies the change in one step, so changes made at the same time by
+/// different gates or the command line cannot remove the last admin together.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminGuardedChange {
+    SetUserRole(SetUserRolePayload),
+    SuspendUser(SuspendUserPayload),
+}
+
+/// The outcome of an `AdminGuardedChange`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminGuardedOutcome {
+    /// The change was applied, the user had `previous_role` before
+    Applied { previous_role: Role },
+    /// The change was refused because no admin would remain
+    LastAdmin,
+}
+
+/// Allow `user_id` to moderate `category_id`, or revoke that permission
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeratorGrantPayload {
+    pub user_id: UserId,
+    pub category_id: CategoryId,
+}
 
 service! {
     rpc authenticate(payload: AuthPayload) -> Token | AuthError;
     rpc deauthenticate(payload: Token) -> () | AuthError;
+    rpc record_session(payload: SessionContextPayload) -> () | AuthError;
+    rpc get_sessions(payload: Token) -> Vec<SessionPayload> | AuthError;
+    rpc revoke_session(payload: RevokeSessionPayload) -> () | AuthError;
+    rpc revoke_other_sessions(payload: Token) -> () | AuthError;
+    rpc rotate_session(payload: Token) -> Token | AuthError;
     rpc register(payload: RegisterUserPayload) -> AddUserPayload | AuthError;
     rpc get_user(payload: Token) -> (UserId, Role) | AuthError;
+    rpc get_all_users(payload: ()) -> Vec<AddUserPayload> | AuthError;
+    rpc delete_user(payload: UserId) -> () | AuthError;
+    rpc get_user_role(payload: UserId) -> Role | AuthError;
+    /// The id of the user with this username, if there is one
+    rpc find_user(payload: String) -> Option<UserId> | AuthError;
+    rpc get_users_with_role(payload: Role) -> Vec<UserId> | AuthError;
     rpc set_user_role(payload: SetUserRolePayload) -> () | AuthError;
+    rpc suspend_user(payload: SuspendUserPayload) -> () | AuthError;
+    rpc apply_keeping_admin(payload: AdminGuardedChange) -> AdminGuardedOutcome | AuthError;
+    rpc get_moderator_grants(payload: UserId) -> Vec<CategoryId> | AuthError;
+    rpc get_all_moderator_grants(payload: ()) -> Vec<ModeratorGrantPayload> | AuthError;
+    rpc grant_moderator(payload: ModeratorGrantPayload) -> () | AuthError;
+    rpc revoke_moderator(payload: ModeratorGrantPayload) -> () | AuthError;
+    rpc get_totp(payload: UserId) -> Option<TotpPayload> | AuthError;
+    rpc set_totp(payload: SetTotpPayload) -> () | AuthError;
+
+    rpc create_api_key(payload: CreateApiKeyPayload) -> ApiKeyPayload | AuthError;
+    rpc get_api_key(payload: String) -> (ApiKeyPayload, Role) | AuthError;
+    rpc get_api_keys(payload: UserId) -> Vec<ApiKeyPayload> | AuthError;
+    rpc revoke_api_key(payload: RevokeApiKeyPayload) -> () | AuthError;
+
+    rpc create_invite(payload: CreateInvitePayload) -> InvitePayload | AuthError;
+    rpc get_invite(payload: String) -> InvitePayload | AuthError;
+    rpc get_invites(payload: ()) -> Vec<InvitePayload> | AuthError;
+    /// Check that the invite is usable and hold one of its uses in the same
+    /// step, returning the reservation
+    rpc reserve_invite(payload: String) -> String | AuthError;
+    rpc release_invite(payload: String) -> () | AuthError;
+    rpc redeem_invite(payload: RedeemInvitePayload) -> () | AuthError;
+    rpc revoke_invite(payload: String) -> () | AuthError;
+
+    rpc change_password(payload: ChangePasswordPayload) -> () | AuthError;
+    rpc request_password_reset(payload: RequestPasswordResetPayload) -> PasswordResetTokenPayload | AuthError;
+    rpc reset_password(payload: ResetPasswordPayload) -> () | AuthError;
+    rpc get_account(payload: UserId) -> AccountPayload | AuthError;
+    /// The account a reset token was issued for, without using up the token
+    rpc get_reset_account(payload: ResetToken) -> AccountPayload | AuthError;
 }
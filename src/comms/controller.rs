@@ -1,27 +1,67 @@
 use datatypes::content::requests::*;
 use datatypes::content::responses::*;
+use datatypes::valid::ids::UserId;
+
+/// Which part of a list to return, sent next to the payload of a list request
+///
+/// The first `offset` items are skipped and at most `limit` items returned.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PageRequest {
+    pub offset: u32,
+    pub limit: u32,
+}
+
+/// The order of a listing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SortOrder {
+    Newest,
+    Oldest,
+    /// Most comments first
+    MostActive,
+}
+
+impl Default for SortOrder {
+    fn default() -> SortOrder {
+        SortOrder::Newest
+    }
+}
+
+/// Which items of a listing to return, and in which order
+///
+/// Timestamps are seconds since the unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ListingFilter {
+    pub sort: SortOrder,
+    pub author: Option<UserId>,
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+}
 
 service! {
     rpc get_user(payload: GetUserPayload) -> UserPayload | ContentError;
+    rpc get_all_users(payload: ()) -> Vec<UserPayload> | ContentError;
     rpc add_user(payload: AddUserPayload) -> UserPayload | ContentError;
+    /// Remove the profile of a user which has no credentials
+    rpc delete_user(payload: UserId) -> () | ContentError;
     rpc edit_user(payload: EditUserPayload) -> UserPayload | ContentError;
 
     rpc get_category(payload: GetCategoryPayload) -> CategoryPayload | ContentError;
-    rpc get_all_categories(payload: GetHiddenPayload) -> Vec<CategoryPayload> | ContentError;
+    rpc get_all_categories(payload: GetHiddenPayload, page: PageRequest) -> Vec<CategoryPayload> | ContentError;
     rpc add_category(payload: AddCategoryPayload) -> CategoryPayload | ContentError;
     rpc edit_category(payload: EditCategoryPayload) -> CategoryPayload | ContentError;
     rpc hide_category(payload: HideCategoryPayload) -> CategoryPayload | ContentError;
 
     rpc get_thread(payload: GetThreadPayload) -> ThreadPayload | ContentError;
-    rpc get_threads_in_category(payload: GetThreadsPayload) -> Vec<ThreadPayload> | ContentError;
-    rpc get_all_threads(payload: GetHiddenPayload) -> Vec<ThreadPayload> | ContentError;
+    rpc get_threads_in_category(payload: GetThreadsPayload, page: PageRequest, filter: ListingFilter) -> Vec<ThreadPayload> | ContentError;
+    rpc get_all_threads(payload: GetHiddenPayload, page: PageRequest) -> Vec<ThreadPayload> | ContentError;
     rpc add_thread(payload: AddThreadPayload) -> ThreadPayload | ContentError;
     rpc edit_thread(payload: EditThreadPayload) -> ThreadPayload | ContentError;
     rpc hide_thread(payload: HideThreadPayload) -> ThreadPayload | ContentError;
 
     rpc get_comment(payload: GetCommentPayload) -> CommentPayload | ContentError;
-    rpc get_comments_in_thread(payload: GetCommentsPayload) -> Vec<CommentPayload> | ContentError;
-    rpc get_all_comments(payload: GetHiddenPayload) -> Vec<CommentPayload> | ContentError;
+    rpc get_comments_in_thread(payload: GetCommentsPayload, page: PageRequest, filter: ListingFilter) -> Vec<CommentPayload> | ContentError;
+    rpc get_all_comments(payload: GetHiddenPayload, page: PageRequest) -> Vec<CommentPayload> | ContentError;
     rpc add_comment(payload: AddCommentPayload) -> CommentPayload | ContentError;
     rpc edit_comment(payload: EditCommentPayload) -> CommentPayload | ContentError;
     rpc hide_comment(payload: HideCommentPayload) -> CommentPayload | ContentError;
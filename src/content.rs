@@ -1,4 +1,6 @@
 //! API-routes to manage content.
+use rocket::http::RawStr;
+use rocket::request::FromParam;
 use rocket::response::NamedFile;
 use rocket_contrib::Json;
 use std::net::{SocketAddr, ToSocketAddrs};
@@ -11,12 +13,26 @@ use datatypes::content::responses::*;
 use datatypes::error::ResponseError;
 use datatypes::valid::fields::*;
 use datatypes::valid::ids::*;
-use datatypes::valid::token::Token;
 
-use crate::auth::connect_to_auth;
+use crate::comms::auth::ApiScope;
 use crate::comms::controller::SyncClient as ControllerClient;
+use crate::comms::controller::{ListingFilter, SortOrder};
+use crate::error::{ApiError, ApiResult, GateError, InvalidParameterPayload, LegacyResult};
+use crate::guards::AuthenticatedUser;
+use crate::markdown::{Render, RenderedComment, RenderedSuccess, RenderedThread};
+use crate::moderators;
+use crate::pagination::{PageQuery, Paged};
+use crate::policy::{self, Action, Target};
+use crate::read_cache::{category_tag, thread_tag, CATEGORIES, READ_CACHE, THREADS};
+use crate::sanitize;
 use crate::JsonResponseResult;
 
+/// A page of a list, see `pagination`
+type PagedResult = Result<Paged<Json<ContentSuccess>>, ApiError>;
+/// A page of a list with errors sent with status 200 like a
+/// `JsonResponseResult`, see `LegacyResult`
+type LegacyPagedResult<T> = Result<Paged<Json<T>>, Json<ApiError>>;
+
 lazy_static! {
     static ref CONTROLLER_IP: SocketAddr = match std::env::var("CONTROLLER_ADDRESS") {
         Ok(value) => value
@@ -39,18 +55,93 @@ pub fn connect_to_controller() -> Result<ControllerClient, ResponseError> {
 }
 
 // Check if user is admin or moderator
-fn is_admin_or_mod(token: Option<Token>) -> Result<bool, ResponseError> {
-    token.map_or(Ok(false), |t| {
-        Ok(connect_to_auth()
-            .map_err(|e| {
-                error!("Failed to connect to auth service: {:?}", e);
-                e
-            })?.get_user(t)?
-            .1
-            >= Role::Moderator)
+fn is_admin_or_mod(user: &Option<AuthenticatedUser>) -> bool {
+    user.as_ref().map_or(false, |u| {
+        u.role >= Role::Moderator && u.has_scope(&ApiScope::ReadOnly)
     })
 }
 
+// The categories the user was granted moderation of, empty for guests
+fn moderated_categories(
+    user: &Option<AuthenticatedUser>,
+) -> Result<Vec<CategoryId>, ResponseError> {
+    match user {
+        Some(u) if u.has_scope(&ApiScope::ReadOnly) => moderators::grants(u),
+        _ => Ok(Vec::new()),
+    }
+}
+
+// Check if user may see hidden content in a category, as admin or moderator
+// of the whole forum or of this category
+fn is_mod_of(
+    user: &Option<AuthenticatedUser>,
+    category: CategoryId,
+) -> Result<bool, ResponseError> {
+    if is_admin_or_mod(user) {
+        return Ok(true);
+    }
+    Ok(moderated_categories(user)?.contains(&category))
+}
+
+// Like `is_mod_of`, for the category of the thread `id`
+fn is_mod_of_thread(
+    user: &Option<AuthenticatedUser>,
+    id: ThreadId,
+) -> Result<bool, ResponseError> {
+    if is_admin_or_mod(user) {
+        return Ok(true);
+    }
+    // Only look up the thread if the user moderates any category
+    let grants = moderated_categories(user)?;
+    if grants.is_empty() {
+        return Ok(false);
+    }
+    let thread = get_any_thread(&connect_to_controller()?, id)?;
+    Ok(grants.contains(&thread.category_id))
+}
+
+// The hidden thread `id`, if the user moderates its category
+//
+// Only used after the thread could not be read without hidden content, so
+// the grants are not looked up for visible threads.
+fn hidden_thread(
+    user: &Option<AuthenticatedUser>,
+    id: ThreadId,
+) -> Result<Option<ThreadPayload>, ResponseError> {
+    let grants = moderated_categories(user)?;
+    if grants.is_empty() {
+        return Ok(None);
+    }
+    Ok(get_any_thread(&connect_to_controller()?, id)
+        .ok()
+        .filter(|thread| grants.contains(&thread.category_id)))
+}
+
+// The hidden comment `id`, if the user moderates the category of its thread
+fn hidden_comment(
+    controller: &ControllerClient,
+    user: &Option<AuthenticatedUser>,
+    id: CommentId,
+) -> Result<Option<CommentPayload>, ResponseError> {
+    let grants = moderated_categories(user)?;
+    if grants.is_empty() {
+        return Ok(None);
+    }
+    let comment = match controller.get_comment(GetCommentPayload {
+        id,
+        include_hidden: true,
+    }) {
+        Ok(comment) => comment,
+        Err(_) => return Ok(None),
+    };
+    let thread = get_any_thread(controller, comment.thread_id)?;
+    if grants.contains(&thread.category_id) {
+        Ok(Some(comment))
+    } else {
+        Ok(None)
+    }
+}
+
 /// Get the main webpage
 ///
 /// This function returns the content of the webpage as html/css/javascript.
@@ -110,14 +201,14 @@ fn static_file(file: PathBuf) -> Option<NamedFile> {
 #[get("/search?<search_form>")]
 fn search(
     search_form: Option<SearchForm>,
-    opt_token: Option<Token>,
+    user: Option<AuthenticatedUser>,
 ) -> JsonResponseResult<ContentSuccess> {
     let search_form = search_form
         .ok_or(ContentError::InvalidSearchQuery)
         .map_err(|e| Json(e.into()))?; // If invalid query.
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
+    let include_hidden: bool = is_admin_or_mod(&user);
     let search_req: SearchPayload = SearchPayload {
         query: search_form.q,
         include_hidden,
@@ -191,7 +282,7 @@ impl Into<SearchPayload> for SearchForm {
 #[get("/category/<id>")]
 fn get_category(
     id: Option<CategoryId>,
-    opt_token: Option<Token>,
+    user: Option<AuthenticatedUser>,
 ) -> JsonResponseResult<ContentSuccess> {
     let id = id
         .ok_or(ContentError::InvalidId)
@@ -200,13 +291,22 @@ fn get_category(
     info!("Requesting category with id {}", id);
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
+    let include_hidden: bool = is_admin_or_mod(&user);
     let category_payload: GetCategoryPayload = GetCategoryPayload { id, include_hidden };
 
-    connect_to_controller()
-        .map_err(Json)?
-        .get_category(category_payload)
+    let controller = connect_to_controller().map_err(Json)?;
+    let result = match controller.get_category(category_payload) {
+        // Moderators of the category only need to be looked up if it is hidden
+        Err(tarpc::Error::App(_)) if !include_hidden && is_mod_of(&user, id).map_err(Json)? => {
+            controller.get_category(GetCategoryPayload {
+                id,
+                include_hidden: true,
+            })
+        }
+        result => result,
+    };
+
+    result
         .map(|v| {
             info!("Returning success from 'get-category' request");
             Json(ContentSuccess::Category(v))
@@ -216,7 +316,11 @@ fn get_category(
         })
 }
 
-/// Get all categories (limited)
+/// Get all categories (paginated)
+///
+/// Takes the optional query parameters `limit` and `cursor`, see `pagination`.
+/// If there are more categories, the `Link` header of the response points at
+/// the next page.
 ///
 /// # Error
 ///
@@ -235,10 +339,15 @@ fn get_category(
 /// ## Query
 ///
 /// ´´´text
-/// localhost:9234/api/categories
+/// localhost:9234/api/categories?limit=4
 /// ´´´
 ///
 /// ## Result
+///
+/// ´´´text
+/// Link: </api/categories?limit=4&cursor=4>; rel="next"
+/// ´´´
+///
 /// ´´´json
 /// {
 ///     "type": "CATEGORIES",
@@ -273,50 +382,214 @@ fn get_category(
 /// }
 /// ´´´
 #[get("/categories")]
-fn get_categories(opt_token: Option<Token>) -> JsonResponseResult<ContentSuccess> {
+fn get_categories(
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> LegacyPagedResult<ContentSuccess> {
+    categories(page, user).map_err(Json)
+}
+
+fn categories(
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> PagedResult {
+    let page = page?; // If invalid page give error.
+
     info!("Requesting all categories");
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
-    let hidden_payload: GetHiddenPayload = GetHiddenPayload { include_hidden };
-
-    connect_to_controller()
-        .map_err(Json)?
-        .get_all_categories(hidden_payload)
-        .map(|v| {
+    let include_hidden: bool = is_admin_or_mod(&user);
+    let hidden_payload = GetHiddenPayload { include_hidden };
+    let page_request = page.request();
+
+    // Reads without hidden content are the same for everyone
+    let key = if include_hidden {
+        None
+    } else {
+        Some(format!("categories:{:?}", page_request))
+    };
+    READ_CACHE
+        .get_or_load(key, |_| vec![CATEGORIES.to_string()], || {
+            Ok(connect_to_controller()?.get_all_categories(hidden_payload, page_request)?)
+        }).map(|v| {
             info!("Returning success from 'get-categories' request");
-            Json(ContentSuccess::Categories(v))
-        }).map_err(|e| {
+            let (categories, next) = page.split(v);
+            Paged {
+                inner: Json(ContentSuccess::Categories(categories)),
+                next,
+            }
+        }).map_err(|e: ApiError| {
             error!("Unable to 'get-categories': {:?}", e);
-            Json(e.into())
+            e
         })
 }
 
-/// Get the threads of a specific category
-#[get("/category/<id>/threads")]
+/// Sorting and filtering of thread and comment listings
+///
+/// * `sort`: `newest` (default), `oldest` or, for threads, `most_active`
+/// * `author`: only items of this user id
+/// * `created_after`, `created_before`: seconds since the unix epoch
+///
+/// `limit`, `cursor` and `render` are read by `pagination` and `markdown`,
+/// they are only listed so the form accepts them.
+#[derive(FromForm, Debug)]
+pub struct ListingForm {
+    sort: Option<String>,
+    author: Option<String>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    limit: Option<String>,
+    cursor: Option<String>,
+    render: Option<String>,
+}
+
+const THREAD_SORTS: &[(&str, SortOrder)] = &[
+    ("newest", SortOrder::Newest),
+    ("oldest", SortOrder::Oldest),
+    ("most_active", SortOrder::MostActive),
+];
+const COMMENT_SORTS: &[(&str, SortOrder)] =
+    &[("newest", SortOrder::Newest), ("oldest", SortOrder::Oldest)];
+
+fn invalid_parameter(parameter: &str, reason: &str) -> GateError {
+    info!("Rejected query parameter '{}': {}", parameter, reason);
+    GateError::InvalidQueryParameter(InvalidParameterPayload {
+        parameter: parameter.to_string(),
+        reason: reason.to_string(),
+    })
+}
+
+fn parse_timestamp(parameter: &str, value: &Option<String>) -> Result<Option<i64>, GateError> {
+    match value {
+        Some(value) => value.parse::<i64>().map(Some).map_err(|_| {
+            invalid_parameter(parameter, "expected seconds since the unix epoch")
+        }),
+        None => Ok(None),
+    }
+}
+
+impl ListingForm {
+    /// Check the form, allowing the sort orders in `sorts`
+    fn filter(&self, sorts: &[(&str, SortOrder)]) -> Result<ListingFilter, GateError> {
+        let sort = match &self.sort {
+            Some(value) => sorts
+                .iter()
+                .find(|(name, _)| *name == value.as_str())
+                .map(|(_, sort)| *sort)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = sorts.iter().map(|(name, _)| *name).collect();
+                    invalid_parameter("sort", &format!("expected one of {}", names.join(", ")))
+                })?,
+            None => SortOrder::default(),
+        };
+        let author = match &self.author {
+            Some(value) => Some(
+                UserId::from_param(RawStr::from_str(value))
+                    .map_err(|_| invalid_parameter("author", "expected a user id"))?,
+            ),
+            None => None,
+        };
+        let created_after = parse_timestamp("created_after", &self.created_after)?;
+        let created_before = parse_timestamp("created_before", &self.created_before)?;
+
+        if let (Some(after), Some(before)) = (created_after, created_before) {
+            if after >= before {
+                return Err(invalid_parameter(
+                    "created_before",
+                    "must be later than created_after",
+                ));
+            }
+        }
+        Ok(ListingFilter {
+            sort,
+            author,
+            created_after,
+            created_before,
+        })
+    }
+}
+
+/// Check a listing form, which is missing if the query has unknown parameters
+fn listing_filter(
+    form: Option<ListingForm>,
+    sorts: &[(&str, SortOrder)],
+) -> Result<ListingFilter, GateError> {
+    form.ok_or_else(|| invalid_parameter("query", "unknown parameter"))?
+        .filter(sorts)
+}
+
+/// Get the threads of a specific category (paginated)
+///
+/// Can be sorted and filtered with the query parameters of `ListingForm`.
+///
+/// # Example
+///
+/// ## Query
+///
+/// ´´´text
+/// localhost:9234/api/category/3/threads?sort=most_active&author=12
+/// ´´´
+#[get("/category/<id>/threads?<form>")]
 fn get_threads_category(
     id: Option<CategoryId>,
-    opt_token: Option<Token>,
-) -> JsonResponseResult<ContentSuccess> {
-    let id = id
-        .ok_or(ContentError::InvalidId)
-        .map_err(|e| Json(e.into()))?; // If invalid id give error.
+    form: Option<ListingForm>,
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> LegacyPagedResult<ContentSuccess> {
+    threads_of_category(id, listing_filter(form, THREAD_SORTS), page, user).map_err(Json)
+}
+
+/// Get the threads of a specific category without a query
+#[get("/category/<id>/threads", rank = 1)]
+fn get_threads_category_unfiltered(
+    id: Option<CategoryId>,
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> LegacyPagedResult<ContentSuccess> {
+    threads_of_category(id, Ok(ListingFilter::default()), page, user).map_err(Json)
+}
+
+fn threads_of_category(
+    id: Option<CategoryId>,
+    filter: Result<ListingFilter, GateError>,
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> PagedResult {
+    let id = id.ok_or(ContentError::InvalidId)?; // If invalid id give error.
+    let page = page?; // If invalid page give error.
+    let filter = filter?; // If invalid sorting or filtering give error.
 
     info!("Requesting all threads from category with id {:?}", id);
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
-    let threads_payload: GetThreadsPayload = GetThreadsPayload { id, include_hidden };
-
-    connect_to_controller()
-        .map_err(Json)?
-        .get_threads_in_category(threads_payload)
-        .map(|v| {
+    let include_hidden: bool = is_mod_of(&user, id)?;
+    let threads_payload = GetThreadsPayload { id, include_hidden };
+    let page_request = page.request();
+
+    // Reads without hidden content are the same for everyone
+    let key = if include_hidden {
+        None
+    } else {
+        Some(format!("threads:{:?}:{:?}:{:?}", id, page_request, filter))
+    };
+    let tags = vec![THREADS.to_string(), category_tag(id)];
+    READ_CACHE
+        .get_or_load(key, |_| tags, || {
+            Ok(connect_to_controller()?.get_threads_in_category(
+                threads_payload,
+                page_request,
+                filter,
+            )?)
+        }).map(|v| {
             info!("Returning success from 'get-threads-of-category' request");
-            Json(ContentSuccess::Threads(v))
-        }).map_err(|e| {
+            let (threads, next) = page.split(v);
+            Paged {
+                inner: Json(ContentSuccess::Threads(threads)),
+                next,
+            }
+        }).map_err(|e: ApiError| {
             error!("Unable to 'get-threads-of-category': {:?}", e);
-            Json(e.into())
+            e
         })
 }
 
@@ -356,79 +629,181 @@ fn get_threads_category(
 ///         "timestamp": 201820131206
 ///     }
 /// }
+/// ´´´
+///
+/// With `?render=true` the description is also included as HTML, see
+/// `markdown`:
+///
+/// ´´´json
+/// {
+///     "type": "THREAD",
+///     "payload": {
+///         ...
+///         "description": "If you want to say *hello*, do it here.",
+///         "rendered": "<p>If you want to say <em>hello</em>, do it here.</p>\n"
+///     }
+/// }
+/// ´´´
 #[get("/thread/<id>")]
 fn get_thread(
     id: Option<ThreadId>,
-    opt_token: Option<Token>,
-) -> JsonResponseResult<ContentSuccess> {
-    let id = id
-        .ok_or(ContentError::InvalidId)
-        .map_err(|e| Json(e.into()))?; // If invalid id, give error.
+    user: Option<AuthenticatedUser>,
+    render: Result<Render, GateError>,
+) -> LegacyResult<RenderedSuccess> {
+    thread(id, user, render).map_err(Json)
+}
+
+fn thread(
+    id: Option<ThreadId>,
+    user: Option<AuthenticatedUser>,
+    render: Result<Render, GateError>,
+) -> ApiResult<RenderedSuccess> {
+    let id = id.ok_or(ContentError::InvalidId)?; // If invalid id, give error.
+    let render = render?; // If invalid render parameter give error.
 
     info!("Getting thread with id {:?}", id);
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
+    let include_hidden: bool = is_admin_or_mod(&user);
     let thread_payload: GetThreadPayload = GetThreadPayload { id, include_hidden };
 
-    connect_to_controller()
-        .map_err(Json)?
-        .get_thread(thread_payload)
+    // Reads without hidden content are the same for everyone
+    let key = if include_hidden {
+        None
+    } else {
+        Some(format!("thread:{:?}", id))
+    };
+    // Hiding the category of the thread also hides the thread
+    let tags = |v: &ThreadPayload| vec![thread_tag(id), category_tag(v.category_id)];
+    let result = READ_CACHE.get_or_load(key, tags, || {
+        Ok(connect_to_controller()?.get_thread(thread_payload)?)
+    });
+    let result = match result {
+        // Moderators of the category only need to be looked up if the thread is hidden
+        Err(e) if !include_hidden => hidden_thread(&user, id)?.ok_or(e),
+        result => result,
+    };
+
+    result
         .map(|v| {
             info!("Returning success from 'get-thread' request");
-            Json(ContentSuccess::Thread(v))
-        }).map_err(|e| {
+            Json(RenderedSuccess::Thread(RenderedThread::new(v, render)))
+        }).map_err(|e: ApiError| {
             error!("Unable to 'get-thread': {:?}", e);
-            Json(e.into())
+            e
         })
 }
 
-/// Get all threads (limited)
+/// Get all threads (paginated)
 #[get("/threads")]
-fn get_threads(opt_token: Option<Token>) -> JsonResponseResult<ContentSuccess> {
+fn get_threads(
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> LegacyPagedResult<ContentSuccess> {
+    threads(page, user).map_err(Json)
+}
+
+fn threads(
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> PagedResult {
+    let page = page?; // If invalid page give error.
+
     info!("Requesting all threads");
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
-    let hidden_payload: GetHiddenPayload = GetHiddenPayload { include_hidden };
+    let include_hidden: bool = is_admin_or_mod(&user);
+    let hidden_payload = GetHiddenPayload { include_hidden };
 
-    connect_to_controller()
-        .map_err(Json)?
-        .get_all_threads(hidden_payload)
+    connect_to_controller()?
+        .get_all_threads(hidden_payload, page.request())
         .map(|v| {
             info!("Returning success from 'get-threads' request");
-            Json(ContentSuccess::Threads(v))
+            let (threads, next) = page.split(v);
+            Paged {
+                inner: Json(ContentSuccess::Threads(threads)),
+                next,
+            }
         }).map_err(|e| {
             error!("Unable to 'get-threads': {:?}", e);
-            Json(e.into())
+            e.into()
         })
 }
 
-/// Get a threads comments.
-#[get("/thread/<id>/comments")]
+/// Get a threads comments (paginated).
+///
+/// Can be sorted and filtered with the query parameters of `ListingForm`,
+/// but not sorted by `most_active`. With `render=true` each comment also
+/// includes its content as HTML in `rendered`, see `markdown`.
+///
+/// An invalid parameter gives an error naming it:
+///
+/// ´´´json
+/// {
+///     "type": "INVALID_QUERY_PARAMETER",
+///     "payload": {
+///         "parameter": "sort",
+///         "reason": "expected one of newest, oldest"
+///     }
+/// }
+/// ´´´
+#[get("/thread/<id>/comments?<form>")]
 fn get_comments_in_thread(
     id: Option<ThreadId>,
-    opt_token: Option<Token>,
-) -> JsonResponseResult<ContentSuccess> {
-    let id = id
-        .ok_or(ContentError::InvalidId)
-        .map_err(|e| Json(e.into()))?; // If invalid id, give error.
+    form: Option<ListingForm>,
+    page: Result<PageQuery, ContentError>,
+    render: Result<Render, GateError>,
+    user: Option<AuthenticatedUser>,
+) -> LegacyPagedResult<RenderedSuccess> {
+    let filter = listing_filter(form, COMMENT_SORTS);
+    comments_of_thread(id, filter, page, render, user).map_err(Json)
+}
+
+/// Get a threads comments without a query
+#[get("/thread/<id>/comments", rank = 1)]
+fn get_comments_in_thread_unfiltered(
+    id: Option<ThreadId>,
+    page: Result<PageQuery, ContentError>,
+    render: Result<Render, GateError>,
+    user: Option<AuthenticatedUser>,
+) -> LegacyPagedResult<RenderedSuccess> {
+    comments_of_thread(id, Ok(ListingFilter::default()), page, render, user).map_err(Json)
+}
+
+fn comments_of_thread(
+    id: Option<ThreadId>,
+    filter: Result<ListingFilter, GateError>,
+    page: Result<PageQuery, ContentError>,
+    render: Result<Render, GateError>,
+    user: Option<AuthenticatedUser>,
+) -> Result<Paged<Json<RenderedSuccess>>, ApiError> {
+    let id = id.ok_or(ContentError::InvalidId)?; // If invalid id, give error.
+    let page = page?; // If invalid page give error.
+    let filter = filter?; // If invalid sorting or filtering give error.
+    let render = render?; // If invalid render parameter give error.
 
     info!("Requesting all comments from thread with id {:?}", id);
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
-    let comments_payload: GetCommentsPayload = GetCommentsPayload { id, include_hidden };
+    let include_hidden: bool = is_mod_of_thread(&user, id)?;
+    let comments_payload = GetCommentsPayload { id, include_hidden };
 
-    connect_to_controller()
-        .map_err(Json)?
-        .get_comments_in_thread(comments_payload)
+    connect_to_controller()?
+        .get_comments_in_thread(comments_payload, page.request(), filter)
         .map(|v| {
             info!("Returning success from 'get-comments-of-thread' request");
-            Json(ContentSuccess::Comments(v))
+            let (comments, next) = page.split(v);
+            let comments = comments
+                .into_iter()
+                .map(|comment| RenderedComment::new(comment, render))
+                .collect();
+            Paged {
+                inner: Json(RenderedSuccess::Comments(comments)),
+                next,
+            }
         }).map_err(|e| {
             error!("Unable to 'get-comments-of-thread': {:?}", e);
-            Json(e.into())
+            e.into()
         })
 }
 
@@ -469,7 +844,7 @@ fn get_comments_in_thread(
 #[get("/comment/<id>")]
 fn get_comment(
     id: Option<CommentId>,
-    opt_token: Option<Token>,
+    user: Option<AuthenticatedUser>,
 ) -> JsonResponseResult<ContentSuccess> {
     let id = id
         .ok_or(ContentError::InvalidId)
@@ -478,12 +853,19 @@ fn get_comment(
     info!("Requesting comment with id {:?}", id);
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
+    let include_hidden: bool = is_admin_or_mod(&user);
     let comment_payload: GetCommentPayload = GetCommentPayload { id, include_hidden };
 
-    connect_to_controller()
-        .map_err(Json)?
-        .get_comment(comment_payload)
+    let controller = connect_to_controller().map_err(Json)?;
+    let result = match controller.get_comment(comment_payload) {
+        // Moderators of the category only need to be looked up if the comment is hidden
+        Err(e @ tarpc::Error::App(_)) if !include_hidden => {
+            hidden_comment(&controller, &user, id).map_err(Json)?.ok_or(e)
+        }
+        result => result,
+    };
+
+    result
         .map(|v| {
             info!("Returning success from 'get-comment' request");
             Json(ContentSuccess::Comment(v))
@@ -493,24 +875,39 @@ fn get_comment(
         })
 }
 
-/// Get all comment (limited)
+/// Get all comment (paginated)
 #[get("/comments")]
-fn get_comments(opt_token: Option<Token>) -> JsonResponseResult<ContentSuccess> {
+fn get_comments(
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> LegacyPagedResult<ContentSuccess> {
+    comments(page, user).map_err(Json)
+}
+
+fn comments(
+    page: Result<PageQuery, ContentError>,
+    user: Option<AuthenticatedUser>,
+) -> PagedResult {
+    let page = page?; // If invalid page give error.
+
     info!("Requesting all comments");
 
     // If logged in as admin/mod, then include hidden elements in result, if not exclude hidden elements.
-    let include_hidden: bool = is_admin_or_mod(opt_token).map_err(|e| Json(e))?;
-    let hidden_payload: GetHiddenPayload = GetHiddenPayload { include_hidden };
+    let include_hidden: bool = is_admin_or_mod(&user);
+    let hidden_payload = GetHiddenPayload { include_hidden };
 
-    connect_to_controller()
-        .map_err(Json)?
-        .get_all_comments(hidden_payload)
+    connect_to_controller()?
+        .get_all_comments(hidden_payload, page.request())
         .map(|v| {
             info!("Returning success from 'get-comments' request");
-            Json(ContentSuccess::Comments(v))
+            let (comments, next) = page.split(v);
+            Paged {
+                inner: Json(ContentSuccess::Comments(comments)),
+                next,
+            }
         }).map_err(|e| {
             error!("Unable to 'get-comments': {:?}", e);
-            Json(e.into())
+            e.into()
         })
 }
 
@@ -571,14 +968,71 @@ fn get_user(id: Option<UserId>) -> JsonResponseResult<ContentSuccess> {
         })
 }
 
+/// Get a thread, including hidden ones
+fn get_any_thread(
+    controller: &ControllerClient,
+    id: ThreadId,
+) -> Result<ThreadPayload, ResponseError> {
+    controller
+        .get_thread(GetThreadPayload {
+            id,
+            include_hidden: true,
+        }).map_err(|e| {
+            error!("Unable to 'get-thread' to check its author: {:?}", e);
+            e.into()
+        })
+}
+
+/// Check the policy for `action` on the thread `id`
+fn authorize_thread(
+    user: &AuthenticatedUser,
+    action: Action,
+    id: ThreadId,
+) -> Result<(), ResponseError> {
+    let thread = get_any_thread(&connect_to_controller()?, id)?;
+    let target = Target {
+        category: thread.category_id,
+        author: Some(thread.user_id),
+    };
+    policy::authorize(action, user, Some(target))
+}
+
+/// Check the policy for `action` on the comment `id`
+fn authorize_comment(
+    user: &AuthenticatedUser,
+    action: Action,
+    id: CommentId,
+) -> Result<(), ResponseError> {
+    let controller = connect_to_controller()?;
+    let comment = controller
+        .get_comment(GetCommentPayload {
+            id,
+            include_hidden: true,
+        }).map_err(|e| {
+            error!("Unable to 'get-comment' to check its author: {:?}", e);
+            ResponseError::from(e)
+        })?;
+    if comment.user_id == user.id {
+        return policy::authorize(action, user, None);
+    }
+
+    // Moderators are checked against the category of the thread
+    let thread = get_any_thread(&controller, comment.thread_id)?;
+    let target = Target {
+        category: thread.category_id,
+        author: Some(comment.user_id),
+    };
+    policy::authorize(action, user, Some(target))
+}
+
 /// Add some content.
 ///
 /// If you are admin, you can ban or unban users.
 /// Types you can send in:
 /// 'ADDCATEGORY', 'EDITCATEGORY', 'HIDECATEGORY',
 /// 'ADDTHREAD', 'EDITTHREAD', 'HIDETREAD',
-/// 'ADDCOMMENT', 'EDITCOMMENT', 'HIDECOMMENT',
-/// 'UPLOADAVATAR'.
+/// 'ADDCOMMENT', 'EDITCOMMENT', 'HIDECOMMENT', 'EDITUSER'.
+/// Avatars are uploaded through 'api/user/avatar' instead.
 ///
 /// Types I can get back: 'CATEGORY', 'THREAD', 'COMMENT'.
 ///
@@ -626,9 +1080,17 @@ fn get_user(id: Option<UserId>) -> JsonResponseResult<ContentSuccess> {
 ///  }
 ///}
 /// ´´´
+///
+/// Who may send which type is set by the permission policy, see `policy`.
+/// Users who moderate a single category count as moderators for the
+/// categories, threads and comments in it.
+///
+/// Instead of logging in, bots can send an API key as
+/// `Authorization: Bearer <key>`, which only allows the requests covered by
+/// the scopes of the key.
 #[post("/content", format = "application/json", data = "<req>")]
 pub fn post_content(
-    token: Token,
+    user: AuthenticatedUser,
     req: Option<Json<ContentRequest>>,
 ) -> JsonResponseResult<ContentSuccess> {
     use datatypes::content::requests::ContentRequest::*;
@@ -639,25 +1101,20 @@ pub fn post_content(
 
     info!("received json request: {:?}", req);
 
-    // Check what role the user has (and that a user is valid):
-    let (id, role) = connect_to_auth()
-        .map_err(Json)?
-        .get_user(token)
-        .map_err(|e| Json(e.into()))?;
+    let id = user.id;
 
     match req.into_inner() {
         AddCategory(p) => {
             // Relays what is sent back to the user
             // If not allowed to do this, return errormessage:
-            if role < Role::Moderator {
-                Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
-            }
+            policy::authorize(Action::AddCategory, &user, None).map_err(Json)?;
 
             info!("Forwarding a 'add-category' request");
             connect_to_controller()
                 .map_err(Json)?
                 .add_category(p)
                 .map(|v| {
+                    READ_CACHE.invalidate(CATEGORIES);
                     info!("Returning success from 'add-category' request");
                     Json(ContentSuccess::Category(v))
                 }).map_err(|e| {
@@ -668,15 +1125,19 @@ pub fn post_content(
         EditCategory(p) => {
             // Relays what is sent back to the user
             // If not allowed to do this, return errormessage:
-            if role < Role::Moderator {
-                Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
-            }
+            let target = Target {
+                category: p.id,
+                author: None,
+            };
+            policy::authorize(Action::EditCategory, &user, Some(target)).map_err(Json)?;
 
             info!("Forwarding a 'edit-category' request");
             connect_to_controller()
                 .map_err(Json)?
                 .edit_category(p)
                 .map(|v| {
+                    READ_CACHE.invalidate(CATEGORIES);
+                    READ_CACHE.invalidate(&category_tag(v.id));
                     info!("Returning success from 'edit-category' request");
                     Json(ContentSuccess::Category(v))
                 }).map_err(|e| {
@@ -687,15 +1148,19 @@ pub fn post_content(
         HideCategory(p) => {
             // Relays what is sent back to the user
             // If not allowed to do this, return errormessage:
-            if role < Role::Admin {
-                Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
-            }
+            let target = Target {
+                category: p.id,
+                author: None,
+            };
+            policy::authorize(Action::HideCategory, &user, Some(target)).map_err(Json)?;
 
             info!("Forwarding a 'hide-category' request");
             connect_to_controller()
                 .map_err(Json)?
                 .hide_category(p)
                 .map(|v| {
+                    READ_CACHE.invalidate(CATEGORIES);
+                    READ_CACHE.invalidate(&category_tag(v.id));
                     info!("Returning success from 'hide-category' request");
                     Json(ContentSuccess::Category(v))
                 }).map_err(|e| {
@@ -704,6 +1169,9 @@ pub fn post_content(
                 })
         }
         AddThread(mut p) => {
+            policy::authorize(Action::AddThread, &user, None).map_err(Json)?;
+            user.require_scope(&ApiScope::PostThreads).map_err(Json)?;
+
             // Relays what is sent back to the user
 
             // Reject the request if the user has added an incorrect user id
@@ -719,11 +1187,15 @@ pub fn post_content(
             // Set the correct user id
             p.user_id = Some(id);
 
+            // Never forward markup the frontend could execute
+            sanitize::add_thread(&mut p).map_err(|e| Json(e.into()))?;
+
             info!("Forwarding a 'add-thread' request");
             connect_to_controller()
                 .map_err(Json)?
                 .add_thread(p)
                 .map(|v| {
+                    READ_CACHE.invalidate(&category_tag(v.category_id));
                     info!("Returning success from 'add-thread' request");
                     Json(ContentSuccess::Thread(v))
                 }).map_err(|e| {
@@ -732,6 +1204,8 @@ pub fn post_content(
                 })
         }
         EditThread(mut p) => {
+            user.require_scope(&ApiScope::PostThreads).map_err(Json)?;
+
             // Reject the request if the user has added an incorrect user id
             if p.user_id.is_some() && id != p.user_id.unwrap() {
                 warn!(
@@ -742,15 +1216,23 @@ pub fn post_content(
                 Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
             }
 
+            // The thread has to belong to the user, unless the policy allows otherwise
+            authorize_thread(&user, Action::EditThread, p.id).map_err(Json)?;
+
             // Set the correct user id
             p.user_id = Some(id);
 
+            // Never forward markup the frontend could execute
+            sanitize::edit_thread(&mut p).map_err(|e| Json(e.into()))?;
+
             // Relays what is sent back to the user
             info!("Forwarding a 'edit-thread' request");
             connect_to_controller()
                 .map_err(Json)?
                 .edit_thread(p)
                 .map(|v| {
+                    READ_CACHE.invalidate(&thread_tag(v.id));
+                    READ_CACHE.invalidate(&category_tag(v.category_id));
                     info!("Returning success from 'edit-thread' request");
                     Json(ContentSuccess::Thread(v))
                 }).map_err(|e| {
@@ -759,6 +1241,8 @@ pub fn post_content(
                 })
         }
         HideThread(mut p) => {
+            user.require_scope(&ApiScope::PostThreads).map_err(Json)?;
+
             // Reject the request if the user has added an incorrect user id
             if p.user_id.is_some() && id != p.user_id.unwrap() {
                 warn!(
@@ -769,6 +1253,9 @@ pub fn post_content(
                 Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
             }
 
+            // The thread has to belong to the user, unless the policy allows otherwise
+            authorize_thread(&user, Action::HideThread, p.id).map_err(Json)?;
+
             // Set the correct user id
             p.user_id = Some(id);
 
@@ -778,6 +1265,8 @@ pub fn post_content(
                 .map_err(Json)?
                 .hide_thread(p)
                 .map(|v| {
+                    READ_CACHE.invalidate(&thread_tag(v.id));
+                    READ_CACHE.invalidate(&category_tag(v.category_id));
                     info!("Returning success from 'hide-thread' request");
                     Json(ContentSuccess::Thread(v))
                 }).map_err(|e| {
@@ -786,6 +1275,9 @@ pub fn post_content(
                 })
         }
         AddComment(mut p) => {
+            policy::authorize(Action::AddComment, &user, None).map_err(Json)?;
+            user.require_scope(&ApiScope::PostComments).map_err(Json)?;
+
             // Reject the request if the user has added an incorrect user id
             if p.user_id.is_some() && id != p.user_id.unwrap() {
                 warn!(
@@ -799,12 +1291,17 @@ pub fn post_content(
             // Set the correct user id
             p.user_id = Some(id);
 
+            // Never forward markup the frontend could execute
+            sanitize::add_comment(&mut p).map_err(|e| Json(e.into()))?;
+
             // Relays what is sent back to the user
             info!("Forwarding a 'add-comment' request");
             connect_to_controller()
                 .map_err(Json)?
                 .add_comment(p)
                 .map(|v| {
+                    // Thread listings can be sorted by activity
+                    READ_CACHE.invalidate(THREADS);
                     info!("Returning success from 'add-comment' request");
                     Json(ContentSuccess::Comment(v))
                 }).map_err(|e| {
@@ -813,6 +1310,8 @@ pub fn post_content(
                 })
         }
         EditComment(mut p) => {
+            user.require_scope(&ApiScope::PostComments).map_err(Json)?;
+
             // Reject the request if the user has added an incorrect user id
             if p.user_id.is_some() && id != p.user_id.unwrap() {
                 warn!(
@@ -823,9 +1322,15 @@ pub fn post_content(
                 Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
             }
 
+            // The comment has to belong to the user, unless the policy allows otherwise
+            authorize_comment(&user, Action::EditComment, p.id).map_err(Json)?;
+
             // Set the correct user id
             p.user_id = Some(id);
 
+            // Never forward markup the frontend could execute
+            sanitize::edit_comment(&mut p).map_err(|e| Json(e.into()))?;
+
             // Relays what is sent back to the user
             info!("Forwarding a 'edit-comment' request");
             connect_to_controller()
@@ -840,6 +1345,8 @@ pub fn post_content(
                 })
         }
         HideComment(mut p) => {
+            user.require_scope(&ApiScope::PostComments).map_err(Json)?;
+
             // Reject the request if the user has added an incorrect user id
             if p.user_id.is_some() && id != p.user_id.unwrap() {
                 warn!(
@@ -850,6 +1357,9 @@ pub fn post_content(
                 Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
             }
 
+            // The comment has to belong to the user, unless the policy allows otherwise
+            authorize_comment(&user, Action::HideComment, p.id).map_err(Json)?;
+
             // Set the correct user id
             p.user_id = Some(id);
 
@@ -859,6 +1369,8 @@ pub fn post_content(
                 .map_err(Json)?
                 .hide_comment(p)
                 .map(|v| {
+                    // Thread listings can be sorted by activity
+                    READ_CACHE.invalidate(THREADS);
                     info!("Returning success from 'hide-comment' request");
                     Json(ContentSuccess::Comment(v))
                 }).map_err(|e| {
@@ -871,6 +1383,8 @@ pub fn post_content(
             Err(Json(ResponseError::Unauthorized))
         }
         EditUser(mut p) => {
+            policy::authorize(Action::EditUser, &user, None).map_err(Json)?;
+
             // Reject the request if the user has added an incorrect user id
             if p.id.is_some() && id != p.id.unwrap() {
                 warn!(
@@ -881,6 +1395,12 @@ pub fn post_content(
                 Err(ResponseError::Unauthorized).map_err(|e| Json(e))?;
             }
 
+            // Avatars are only set by uploading them through 'api/user/avatar'
+            if p.avatar.is_some() {
+                warn!("User ({:?}) tried to set the avatar with 'edit-user'", id);
+                Err(ContentError::InvalidContent).map_err(|e| Json(e.into()))?;
+            }
+
             // Set the correct user id
             p.id = Some(id);
 
@@ -0,0 +1,30 @@
+//! Small cryptographic helpers shared by the gate.
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+/// Generate `len` cryptographically secure random bytes
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    thread_rng().fill(&mut bytes[..]);
+    bytes
+}
+
+/// Generate a random hex string from `len` random bytes
+pub fn random_hex(len: usize) -> String {
+    to_hex(&random_bytes(len))
+}
+
+/// Lowercase hex representation of `bytes`
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256 digest of `data` as lowercase hex
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Compare two byte strings in time independent of where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,155 @@
+//! Errors returned by the gate.
+//!
+//! Most errors are passed through from the auth service and the controller as
+//! a `ResponseError`. Errors which only the gate knows about are described by
+//! `GateError`. Both serialize to the same `{ "type": ..., "payload": ... }`
+//! shape, so the frontend does not need to know where an error came from.
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use rocket_contrib::Json;
+
+use datatypes::auth::responses::AuthError;
+use datatypes::content::responses::ContentError;
+use datatypes::error::ResponseError;
+
+use crate::avatar::AvatarRejection;
+use crate::password_policy::PasswordRejection;
+
+/// Convenience wrapper around a `Result` of a `Json` value and an `ApiError`
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Like `ApiResult`, but errors are sent with status 200 like a
+/// `JsonResponseResult`, for endpoints whose clients rely on that
+pub type LegacyResult<T> = Result<Json<T>, Json<ApiError>>;
+
+/// A challenge which has to be answered to finish logging in
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChallengePayload {
+    pub challenge: String,
+}
+
+/// A query parameter which was rejected, and why
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InvalidParameterPayload {
+    pub parameter: String,
+    pub reason: String,
+}
+
+/// Errors which originate in the gate itself
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GateError {
+    /// The password was correct, but a TOTP code is needed to finish logging in
+    TotpRequired(ChallengePayload),
+    /// The role of the user requires TOTP, which has to be enrolled before
+    /// the login can finish
+    TotpEnrollmentRequired(ChallengePayload),
+    /// The TOTP code or recovery code was wrong
+    InvalidTotpCode,
+    /// Too many wrong TOTP codes were sent for the user, try again later
+    TotpLockedOut,
+    /// The request has to include the solution to a proof-of-work challenge
+    ProofOfWorkRequired,
+    /// The proof-of-work challenge is unknown, expired or not solved
+    InvalidProofOfWork,
+    /// No new users are accepted right now
+    RegistrationClosed,
+    /// Registration is invite-only and the request has no invite code
+    InviteRequired,
+    /// The invite code is unknown, expired or used up
+    InvalidInvite,
+    /// The new password does not satisfy the password policy
+    PasswordRejected(PasswordRejection),
+    /// The action is sensitive and the credentials have to be confirmed first
+    ReauthenticationRequired,
+    /// The role change would leave the forum without an admin
+    LastAdmin,
+    /// Admins have to confirm lowering their own role
+    SelfDemotionNotConfirmed,
+    /// A query parameter is unknown or has an invalid value
+    InvalidQueryParameter(InvalidParameterPayload),
+    /// The uploaded avatar is not an acceptable image
+    AvatarRejected(AvatarRejection),
+}
+
+impl GateError {
+    fn status(&self) -> Status {
+        use self::GateError::*;
+        match self {
+            TotpRequired(_) | TotpEnrollmentRequired(_) | InvalidTotpCode => Status::Unauthorized,
+            TotpLockedOut => Status::TooManyRequests,
+            ProofOfWorkRequired | InvalidProofOfWork => Status::Forbidden,
+            RegistrationClosed | InviteRequired | InvalidInvite => Status::Forbidden,
+            PasswordRejected(_) => Status::BadRequest,
+            ReauthenticationRequired => Status::Forbidden,
+            LastAdmin | SelfDemotionNotConfirmed => Status::Conflict,
+            InvalidQueryParameter(_) => Status::BadRequest,
+            AvatarRejected(AvatarRejection::TooLarge { .. }) => Status::PayloadTooLarge,
+            AvatarRejected(AvatarRejection::TooManyUploads { .. }) => Status::TooManyRequests,
+            AvatarRejected(_) => Status::BadRequest,
+        }
+    }
+}
+
+/// Any error the gate may respond with
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ApiError {
+    Response(ResponseError),
+    Gate(GateError),
+}
+
+impl ApiError {
+    fn status(&self) -> Status {
+        match self {
+            ApiError::Response(ResponseError::Unauthenticated) => Status::Unauthorized,
+            ApiError::Response(ResponseError::Unauthorized) => Status::Forbidden,
+            ApiError::Response(ResponseError::InternalServerError) => Status::InternalServerError,
+            ApiError::Response(_) => Status::BadRequest,
+            ApiError::Gate(e) => e.status(),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let status = self.status();
+        Response::build_from(Json(self).respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
+
+impl From<ResponseError> for ApiError {
+    fn from(e: ResponseError) -> ApiError {
+        ApiError::Response(e)
+    }
+}
+
+impl From<GateError> for ApiError {
+    fn from(e: GateError) -> ApiError {
+        ApiError::Gate(e)
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> ApiError {
+        ApiError::Response(e.into())
+    }
+}
+
+impl From<ContentError> for ApiError {
+    fn from(e: ContentError) -> ApiError {
+        ApiError::Response(e.into())
+    }
+}
+
+impl<E> From<tarpc::Error<E>> for ApiError
+where
+    ResponseError: From<tarpc::Error<E>>,
+{
+    fn from(e: tarpc::Error<E>) -> ApiError {
+        ApiError::Response(e.into())
+    }
+}
@@ -0,0 +1,223 @@
+//! Request guards which resolve the credentials of a request to a user.
+//!
+//! A route declares the permissions it needs by taking one of the guards as
+//! an argument. If the guard fails the request is answered by one of the
+//! catchers below, which respond with a JSON error and a matching status.
+//!
+//! A user is identified either by the session cookie or by an API key sent as
+//! `Authorization: Bearer <key>`. API keys are limited to their scopes and are
+//! never accepted by `Moderator` and `Admin`. Sessions are checked against the
+//! context they were created in, see `session_binding`.
+use chrono::prelude::*;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+use rocket_contrib::Json;
+use std::ops::Deref;
+
+use datatypes::auth::responses::Role;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::Token;
+
+use crate::auth::{self, connect_to_auth};
+use crate::comms::auth::{ApiKeyPayload, ApiScope};
+use crate::crypto;
+use crate::session_binding;
+
+/// How a user proved who they are
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Session(Token),
+    ApiKey(ApiKeyPayload),
+}
+
+/// A user with a valid session or API key
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: UserId,
+    pub role: Role,
+    pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+    /// Fail with `Unauthorized` unless the user has at least `role`
+    pub fn require(&self, role: Role) -> Result<(), ResponseError> {
+        if self.role < role {
+            warn!(
+                "User ({:?}) with role {:?} tried to perform an action requiring {:?}",
+                self.id, self.role, role
+            );
+            return Err(ResponseError::Unauthorized);
+        }
+        Ok(())
+    }
+
+    /// Fail with `Unauthorized` if the user uses an API key without `scope`
+    ///
+    /// Sessions are not limited by scopes.
+    pub fn require_scope(&self, scope: &ApiScope) -> Result<(), ResponseError> {
+        match &self.credential {
+            Credential::ApiKey(key) if !self.has_scope(scope) => {
+                warn!(
+                    "API key {:?} of user ({:?}) used without scope {:?}",
+                    key.id, self.id, scope
+                );
+                Err(ResponseError::Unauthorized)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the user may act within `scope`
+    ///
+    /// Sessions are not limited by scopes.
+    pub fn has_scope(&self, scope: &ApiScope) -> bool {
+        match &self.credential {
+            Credential::Session(_) => true,
+            Credential::ApiKey(key) => key.scopes.contains(scope),
+        }
+    }
+
+    /// Get the session token, failing with `Unauthorized` for API keys
+    pub fn session_token(&self) -> Result<&Token, ResponseError> {
+        match &self.credential {
+            Credential::Session(token) => Ok(token),
+            Credential::ApiKey(key) => {
+                warn!(
+                    "API key {:?} of user ({:?}) used for a session-only action",
+                    key.id, self.id
+                );
+                Err(ResponseError::Unauthorized)
+            }
+        }
+    }
+}
+
+/// Resolve an `Authorization: Bearer <key>` header
+fn user_from_api_key(key: &str) -> Result<AuthenticatedUser, ResponseError> {
+    let key_hash = crypto::sha256_hex(key.as_bytes());
+    let (key, role) = connect_to_auth()?.get_api_key(key_hash)?;
+
+    if key.expires.map_or(false, |expires| expires <= Utc::now().timestamp()) {
+        info!("Request with expired API key {:?}", key.id);
+        return Err(ResponseError::Unauthenticated);
+    }
+
+    Ok(AuthenticatedUser {
+        id: key.user_id,
+        role,
+        credential: Credential::ApiKey(key),
+    })
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
+    type Error = ResponseError;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, ResponseError> {
+        let user = match req.guard::<Token>() {
+            Outcome::Success(token) => auth::get_user(token.clone()).and_then(|(id, role)| {
+                let token = session_binding::check(req, token, id, role)?;
+                Ok(AuthenticatedUser {
+                    id,
+                    role,
+                    credential: Credential::Session(token),
+                })
+            }),
+            _ => match req.headers().get_one("Authorization") {
+                Some(value) if value.starts_with("Bearer ") => {
+                    user_from_api_key(value["Bearer ".len()..].trim())
+                }
+                _ => Err(ResponseError::Unauthenticated),
+            },
+        };
+
+        match user {
+            Ok(user) => Outcome::Success(user),
+            Err(ResponseError::InternalServerError) => Outcome::Failure((
+                Status::InternalServerError,
+                ResponseError::InternalServerError,
+            )),
+            Err(e) => {
+                info!("Request with invalid credentials: {:?}", e);
+                Outcome::Failure((Status::Unauthorized, ResponseError::Unauthenticated))
+            }
+        }
+    }
+}
+
+/// Resolve the user of a request and check that it has a session and at least
+/// `role`
+fn user_with_role<'a, 'r>(
+    req: &'a Request<'r>,
+    role: Role,
+) -> request::Outcome<AuthenticatedUser, ResponseError> {
+    let user = match AuthenticatedUser::from_request(req) {
+        Outcome::Success(user) => user,
+        Outcome::Failure(f) => return Outcome::Failure(f),
+        Outcome::Forward(f) => return Outcome::Forward(f),
+    };
+
+    match user.session_token().and_then(|_| user.require(role)) {
+        Ok(()) => Outcome::Success(user),
+        Err(e) => Outcome::Failure((Status::Forbidden, e)),
+    }
+}
+
+/// A user with at least the role `Role::Moderator`
+#[derive(Debug, Clone)]
+pub struct Moderator(pub AuthenticatedUser);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Moderator {
+    type Error = ResponseError;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, ResponseError> {
+        user_with_role(req, Role::Moderator).map(Moderator)
+    }
+}
+
+impl Deref for Moderator {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+/// A user with the role `Role::Admin`
+#[derive(Debug, Clone)]
+pub struct Admin(pub AuthenticatedUser);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ResponseError;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, ResponseError> {
+        user_with_role(req, Role::Admin).map(Admin)
+    }
+}
+
+impl Deref for Admin {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+/// Respond with JSON when a request lacks a valid session
+#[error(401)]
+fn unauthenticated() -> Json<ResponseError> {
+    Json(ResponseError::Unauthenticated)
+}
+
+/// Respond with JSON when a user lacks the required role
+#[error(403)]
+fn unauthorized() -> Json<ResponseError> {
+    Json(ResponseError::Unauthorized)
+}
+
+/// Respond with JSON when a guard was unable to reach the auth service
+#[error(500)]
+fn internal_server_error() -> Json<ResponseError> {
+    Json(ResponseError::InternalServerError)
+}
@@ -87,6 +87,17 @@ pub fn setup_logging(verbosity: u64) -> Result<(), fern::InitError> {
             ))
         }).chain(fern::log_file("security-gate.log")?);
 
+    // Audit events (e.g. role changes) are also kept in their own file
+    let audit_config = fern::Dispatch::new()
+        .filter(|metadata| metadata.target() == "audit")
+        .format(|out, message, _| {
+            out.finish(format_args!(
+                "{} {}",
+                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                message
+            ))
+        }).chain(fern::log_file("security-gate-audit.log")?);
+
     let stdout_config = fern::Dispatch::new()
         .format(|out, message, record| {
             // special format for debug messages coming from our own crate.
@@ -109,6 +120,7 @@ pub fn setup_logging(verbosity: u64) -> Result<(), fern::InitError> {
 
     base_config
         .chain(file_config)
+        .chain(audit_config)
         .chain(stdout_config)
         .apply()?;
 
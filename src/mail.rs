@@ -0,0 +1,102 @@
+//! Outgoing mail used by the password reset flow.
+//!
+//! The gate does not speak SMTP itself. Instead mail is handed to a
+//! `MailSender` which is selected at startup through the `MAIL_SINK`
+//! environment variable:
+//!
+//! - `stdout` (default) prints every mail to the standard output
+//! - `file:<path>` appends every mail to the file at `<path>`
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+
+/// A single outgoing mail
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    /// Format the mail as a plain text message
+    fn render(&self) -> String {
+        format!(
+            "Date: {}\nTo: {}\nSubject: {}\n\n{}\n",
+            Utc::now().to_rfc2822(),
+            self.to,
+            self.subject,
+            self.body
+        )
+    }
+}
+
+/// Something that is able to deliver mail
+pub trait MailSender: Send + Sync {
+    fn send(&self, mail: &Mail) -> io::Result<()>;
+}
+
+/// Prints all mail to stdout, useful during development
+pub struct StdoutSender;
+
+impl MailSender for StdoutSender {
+    fn send(&self, mail: &Mail) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        writeln!(handle, "---\n{}---", mail.render())
+    }
+}
+
+/// Appends all mail to a file
+pub struct FileSender {
+    path: PathBuf,
+    // Prevent mails sent from different requests from interleaving
+    lock: Mutex<()>,
+}
+
+impl FileSender {
+    pub fn new(path: impl Into<PathBuf>) -> FileSender {
+        FileSender {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl MailSender for FileSender {
+    fn send(&self, mail: &Mail) -> io::Result<()> {
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "mail file lock poisoned"))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", mail.render())
+    }
+}
+
+/// Select the mail sender based on the `MAIL_SINK` environment variable
+pub fn sender_from_env() -> Box<dyn MailSender> {
+    match std::env::var("MAIL_SINK") {
+        Ok(ref value) if value.starts_with("file:") => {
+            let path = &value["file:".len()..];
+            info!("Writing outgoing mail to '{}'", path);
+            Box::new(FileSender::new(path))
+        }
+        Ok(ref value) if value == "stdout" => Box::new(StdoutSender),
+        Ok(value) => {
+            warn!("Unknown MAIL_SINK '{}', using 'stdout'", value);
+            Box::new(StdoutSender)
+        }
+        Err(_) => {
+            warn!("MAIL_SINK is not set, using 'stdout'");
+            Box::new(StdoutSender)
+        }
+    }
+}
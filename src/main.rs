@@ -22,17 +22,41 @@ extern crate clap;
 extern crate fern;
 #[macro_use]
 extern crate tarpc;
+#[macro_use]
+extern crate serde_derive;
 
 use rocket::config::{Config, Environment};
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Header;
 use rocket::{Request, Response};
 
+pub mod api_keys;
 pub mod auth;
+pub mod avatar;
 pub mod banned;
+pub mod cli;
 pub mod comms;
 pub mod content;
+pub mod crypto;
+pub mod error;
+pub mod guards;
 pub mod logging;
+pub mod mail;
+pub mod markdown;
+pub mod moderators;
+pub mod pagination;
+pub mod password_policy;
+pub mod policy;
+pub mod pow;
+pub mod read_cache;
+pub mod registration;
+pub mod sanitize;
+pub mod session_binding;
+pub mod sessions;
+pub mod sudo;
+pub mod token_cache;
+pub mod totp;
+pub mod users;
 
 /// Convenience wrapper around a `Result` of `Json` values
 type JsonResponseResult<T> =
@@ -40,20 +64,7 @@ type JsonResponseResult<T> =
 
 fn main() {
     // Logging
-    let cmd_arguments = clap::App::new("security-gate")
-        .arg(
-            clap::Arg::with_name("verbose")
-                .short("v")
-                .long("verbose")
-                .multiple(true)
-                .help("Increases logging verbosity each use for up to 3 times"),
-        ).arg(
-            clap::Arg::with_name("admin")
-                .short("a")
-                .long("admin")
-                .multiple(true)
-                .help("Create an andmin account"),
-        ).get_matches();
+    let cmd_arguments = cli::app().get_matches();
 
     let verbosity: u64 = cmd_arguments.occurrences_of("verbose");
     logging::setup_logging(verbosity).expect("failed to initialize logging.");
@@ -75,10 +86,34 @@ fn main() {
         }
     };
 
+    // Run management commands instead of serving
+    let result = match cmd_arguments.subcommand() {
+        ("user", Some(m)) => Some(cli::user(m)),
+        ("ban", Some(m)) => Some(cli::ban(m)),
+        ("policy", Some(_)) => Some(cli::policy()),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Refuse to serve with a broken policy rather than failing every request
+    if let Err(e) = policy::rules() {
+        error!("Invalid permission policy: {}", e);
+        std::process::exit(1);
+    }
+
     // Create admin
     let admin: u64 = cmd_arguments.occurrences_of("admin");
     if admin >= 1 {
-        auth::create_admin();
+        if let Err(e) = auth::create_admin() {
+            error!("Unable to create admin account: {}", e);
+            std::process::exit(1);
+        }
     }
 
     // Configuring rocket:
@@ -91,16 +126,44 @@ fn main() {
     info!("igniting rocket");
     rocket::custom(config, false)
         .attach(logging::RocketLogger)
-        .attach(banned::BanIpAddrs::default())
+        .attach(banned::BanIpAddrs::load())
         .attach(ModifyResponseHeaders)
+        .attach(session_binding::SessionCookies)
+        .manage(mail::sender_from_env())
+        .catch(errors![
+            guards::unauthenticated,
+            guards::unauthorized,
+            guards::internal_server_error
+        ])
         .mount(
             "/",
-            routes![content::index, content::static_file, banned::banned_message],
+            routes![
+                content::index,
+                content::static_file,
+                avatar::get_avatar,
+                banned::banned_message
+            ],
         ).mount(
             "/api/",
             routes![
                 banned::post_admin,
+                policy::get_policy,
+                moderators::get_moderators,
+                moderators::post_moderators,
+                read_cache::get_cache_metrics,
                 auth::auth,
+                auth::password,
+                auth::me,
+                sudo::reauthenticate,
+                pow::get_challenge,
+                registration::get_registration_mode,
+                registration::get_invites,
+                registration::post_registration,
+                totp::totp,
+                api_keys::get_api_keys,
+                api_keys::post_api_keys,
+                sessions::get_sessions,
+                sessions::post_sessions,
                 content::search,
                 content::get_category,
                 content::get_categories,
@@ -109,8 +172,12 @@ fn main() {
                 content::get_comment,
                 content::get_comments,
                 content::get_threads_category,
+                content::get_threads_category_unfiltered,
                 content::get_comments_in_thread,
+                content::get_comments_in_thread_unfiltered,
                 content::get_user,
+                avatar::post_avatar,
+                markdown::preview,
                 content::post_content
             ],
         ).launch();
@@ -0,0 +1,195 @@
+//! Markdown rendering of thread descriptions and comments.
+//!
+//! Text is rendered with CommonMark and the resulting HTML is cleaned with
+//! the default whitelist of `ammonia`, so rendered text is always safe to
+//! insert into a page. Stored text may contain `<`, `>`, `&`, `"` and `'` as
+//! character references, see `sanitize`. These are decoded before rendering,
+//! so e.g. blockquotes and code spans still work.
+//!
+//! `get_thread` and `get_comments_in_thread` add the rendered text as
+//! `rendered` when asked to with `?render=true`, and `/api/preview` renders
+//! text before it is submitted.
+use pulldown_cmark::{html, Parser};
+use rocket::http::Status;
+use rocket::request::{self, FormItems, FromRequest, Request};
+use rocket::Outcome;
+use rocket_contrib::Json;
+
+use datatypes::content::responses::{CommentPayload, ContentError, ThreadPayload};
+
+use crate::error::{ApiResult, GateError, InvalidParameterPayload};
+use crate::guards::AuthenticatedUser;
+use crate::sanitize;
+
+/// Character references which `sanitize` may put into stored text
+const REFERENCES: &[(&str, char)] = &[
+    ("&lt;", '<'),
+    ("&gt;", '>'),
+    ("&amp;", '&'),
+    ("&quot;", '"'),
+    ("&#x27;", '\''),
+    ("&#39;", '\''),
+];
+
+/// Decode the character references in `text` which `sanitize` produces
+///
+/// The rendered HTML is cleaned anyway, so decoding cannot let HTML through.
+fn decode_references(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        decoded.push_str(&rest[..at]);
+        rest = &rest[at..];
+        match REFERENCES.iter().find(|(reference, _)| rest.starts_with(reference)) {
+            Some((reference, c)) => {
+                decoded.push(*c);
+                rest = &rest[reference.len()..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Render markdown as sanitised HTML
+pub fn render(text: &str) -> String {
+    let text = decode_references(text);
+    let mut rendered = String::with_capacity(text.len() * 3 / 2);
+    html::push_html(&mut rendered, Parser::new(&text));
+    ammonia::clean(&rendered)
+}
+
+/// Whether a response should include rendered text, from the `render` query
+/// parameter
+#[derive(Debug, Clone, Copy)]
+pub struct Render(pub bool);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Render {
+    type Error = GateError;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Render, GateError> {
+        let query = req.uri().query().unwrap_or("");
+        let value = FormItems::from(query)
+            .find(|(key, _)| key.as_str() == "render")
+            .map(|(_, value)| value.as_str());
+
+        match value {
+            None | Some("false") => Outcome::Success(Render(false)),
+            Some("true") => Outcome::Success(Render(true)),
+            Some(_) => Outcome::Failure((
+                Status::BadRequest,
+                GateError::InvalidQueryParameter(InvalidParameterPayload {
+                    parameter: "render".to_string(),
+                    reason: "expected true or false".to_string(),
+                }),
+            )),
+        }
+    }
+}
+
+/// A thread with its description rendered, if asked for
+#[derive(Serialize, Debug, Clone)]
+pub struct RenderedThread {
+    #[serde(flatten)]
+    pub thread: ThreadPayload,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rendered: Option<String>,
+}
+
+impl RenderedThread {
+    pub fn new(thread: ThreadPayload, render: Render) -> RenderedThread {
+        let rendered = if render.0 {
+            Some(self::render(thread.description.as_ref()))
+        } else {
+            None
+        };
+        RenderedThread { thread, rendered }
+    }
+}
+
+/// A comment with its content rendered, if asked for
+#[derive(Serialize, Debug, Clone)]
+pub struct RenderedComment {
+    #[serde(flatten)]
+    pub comment: CommentPayload,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rendered: Option<String>,
+}
+
+impl RenderedComment {
+    pub fn new(comment: CommentPayload, render: Render) -> RenderedComment {
+        let rendered = if render.0 {
+            Some(self::render(comment.content.as_ref()))
+        } else {
+            None
+        };
+        RenderedComment { comment, rendered }
+    }
+}
+
+/// Responses which may contain rendered text
+///
+/// Without rendering these have the same shape as `ContentSuccess`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RenderedSuccess {
+    Thread(RenderedThread),
+    Comments(Vec<RenderedComment>),
+}
+
+/// Text to preview, as it would be submitted
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PreviewRequest {
+    ThreadDescription(String),
+    CommentContent(String),
+}
+
+/// Rendered text to preview
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PreviewSuccess {
+    Preview(String),
+}
+
+/// Render text as it would be shown after submitting it
+///
+/// The text is sanitised like it would be when saved, see `sanitize`, and
+/// then rendered.
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "type": "COMMENT_CONTENT",
+///     "payload": "Hello *everybody*"
+/// }
+/// ´´´
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "type": "PREVIEW",
+///     "payload": "<p>Hello <em>everybody</em></p>\n"
+/// }
+/// ´´´
+#[post("/preview", format = "application/json", data = "<req>")]
+pub fn preview(
+    _user: AuthenticatedUser,
+    req: Option<Json<PreviewRequest>>,
+) -> ApiResult<PreviewSuccess> {
+    let req = req.ok_or(ContentError::InvalidContent)?; // If invalid request give error.
+
+    let text = match req.into_inner() {
+        PreviewRequest::ThreadDescription(text) => sanitize::thread_description(&text),
+        PreviewRequest::CommentContent(text) => sanitize::comment_content(&text),
+    };
+
+    info!("Returning success from 'preview' request");
+    Ok(Json(PreviewSuccess::Preview(render(&text))))
+}
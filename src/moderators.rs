@@ -0,0 +1,169 @@
+//! Moderators of single categories.
+//!
+//! `Role::Moderator` applies to the whole forum. Admins can also grant users
+//! moderation of single categories through `/api/admin/moderators`. Within
+//! such a category the user counts as a moderator when the permission policy
+//! is checked, see `policy`.
+use rocket_contrib::Json;
+
+use datatypes::auth::responses::Role;
+use datatypes::content::responses::ContentError;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::CategoryId;
+
+use crate::auth::connect_to_auth;
+use crate::comms::auth::ModeratorGrantPayload;
+use crate::error::ApiResult;
+use crate::guards::{Admin, AuthenticatedUser};
+use crate::policy::{self, Action};
+use crate::sudo;
+use crate::token_cache::TOKEN_CACHE;
+
+/// The categories `user` was granted moderation of
+///
+/// The grants are kept in the token cache for a short while, the auth
+/// service is only connected to if they are not cached.
+pub fn grants(user: &AuthenticatedUser) -> Result<Vec<CategoryId>, ResponseError> {
+    if let Some(grants) = TOKEN_CACHE.get_grants(user.id) {
+        return Ok(grants);
+    }
+
+    let grants = connect_to_auth()?
+        .get_moderator_grants(user.id)
+        .map_err(|e| {
+            error!("Unable to 'get-moderator-grants': {:?}", e);
+            ResponseError::from(e)
+        })?;
+    TOKEN_CACHE.insert_grants(user.id, grants.clone());
+    Ok(grants)
+}
+
+/// The role of `user` within `category`
+///
+/// Users with a grant for the category count as moderators there.
+pub fn role_in(user: &AuthenticatedUser, category: CategoryId) -> Result<Role, ResponseError> {
+    if user.role >= Role::Moderator {
+        return Ok(user.role);
+    }
+
+    if grants(user)?.contains(&category) {
+        Ok(Role::Moderator)
+    } else {
+        Ok(user.role)
+    }
+}
+
+/// Requests which grant or revoke the moderation of a category
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ModeratorRequest {
+    GrantModerator(ModeratorGrantPayload),
+    RevokeModerator(ModeratorGrantPayload),
+}
+
+/// The outcome of a successful moderator request
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ModeratorSuccess {
+    ModeratorGrants(Vec<ModeratorGrantPayload>),
+    ModeratorGranted,
+    ModeratorRevoked,
+}
+
+/// Get all moderators of single categories
+///
+/// # Example
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "type": "MODERATOR_GRANTS",
+///     "payload": [{
+///         "user_id": 4,
+///         "category_id": 3
+///     }]
+/// }
+/// ´´´
+#[get("/admin/moderators")]
+pub fn get_moderators(_admin: Admin) -> ApiResult<ModeratorSuccess> {
+    connect_to_auth()?
+        .get_all_moderator_grants(())
+        .map(|v| {
+            info!("Returning success from 'get-moderators' request");
+            Json(ModeratorSuccess::ModeratorGrants(v))
+        }).map_err(|e| {
+            error!("Unable to 'get-all-moderator-grants': {:?}", e);
+            e.into()
+        })
+}
+
+/// Grant or revoke the moderation of a category
+///
+/// Like role changes, this needs a recent confirmation of the credentials
+/// through 'api/auth/reauthenticate' and is written to the audit log.
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "type": "GRANT_MODERATOR",
+///     "payload": {
+///         "user_id": 4,
+///         "category_id": 3
+///     }
+/// }
+/// ´´´
+///
+/// Result:
+///
+/// ´´´json
+/// {
+///     "type": "MODERATOR_GRANTED"
+/// }
+/// ´´´
+#[post("/admin/moderators", format = "application/json", data = "<req>")]
+pub fn post_moderators(
+    user: AuthenticatedUser,
+    req: Option<Json<ModeratorRequest>>,
+) -> ApiResult<ModeratorSuccess> {
+    use self::ModeratorRequest::*;
+
+    let req = req.ok_or(ContentError::InvalidContent)?; // If invalid request give error.
+
+    match req.into_inner() {
+        GrantModerator(p) => {
+            policy::authorize(Action::GrantModerator, &user, None)?;
+            sudo::require(&user)?;
+
+            connect_to_auth()?.grant_moderator(p).map_err(|e| {
+                error!("Unable to 'grant-moderator': {:?}", e);
+                ResponseError::from(e)
+            })?;
+            TOKEN_CACHE.invalidate_user(p.user_id);
+            info!(
+                target: "audit",
+                "Moderator grant: user ({:?}) made user ({:?}) moderator of category ({:?})",
+                user.id, p.user_id, p.category_id
+            );
+            Ok(Json(ModeratorSuccess::ModeratorGranted))
+        }
+        RevokeModerator(p) => {
+            policy::authorize(Action::RevokeModerator, &user, None)?;
+            sudo::require(&user)?;
+
+            connect_to_auth()?.revoke_moderator(p).map_err(|e| {
+                error!("Unable to 'revoke-moderator': {:?}", e);
+                ResponseError::from(e)
+            })?;
+            TOKEN_CACHE.invalidate_user(p.user_id);
+            info!(
+                target: "audit",
+                "Moderator revocation: user ({:?}) removed user ({:?}) as moderator of \
+                 category ({:?})",
+                user.id, p.user_id, p.category_id
+            );
+            Ok(Json(ModeratorSuccess::ModeratorRevoked))
+        }
+    }
+}
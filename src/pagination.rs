@@ -0,0 +1,128 @@
+//! Pagination of list endpoints.
+//!
+//! List endpoints accept `limit` and `cursor` query parameters. Without a
+//! `limit`, `PAGE_DEFAULT_LIMIT` (25) items are returned, and any limit is
+//! capped at `PAGE_MAX_LIMIT` (100). If there are more items, the response
+//! carries a `Link: <...>; rel="next"` header pointing at the next page, so
+//! the body keeps the same shape as before.
+//!
+//! This is a breaking change for clients which do not know about pagination:
+//! they only get the first page of longer lists.
+use rocket::http::{Header, Status};
+use rocket::request::{self, FormItems, FromForm, FromRequest, Request};
+use rocket::response::{self, Responder};
+use rocket::Outcome;
+
+use datatypes::content::responses::ContentError;
+
+use crate::comms::controller::PageRequest;
+
+lazy_static! {
+    static ref DEFAULT_LIMIT: u32 = env_number("PAGE_DEFAULT_LIMIT", 25);
+    static ref MAX_LIMIT: u32 = env_number("PAGE_MAX_LIMIT", 100);
+}
+
+fn env_number(name: &str, default: u32) -> u32 {
+    match std::env::var(name).map(|value| value.parse::<u32>()) {
+        Ok(Ok(value)) => value,
+        Ok(Err(_)) => {
+            warn!("{} is not a number, using '{}'", name, default);
+            default
+        }
+        Err(_) => {
+            warn!("{} is not set, using '{}'", name, default);
+            default
+        }
+    }
+}
+
+/// The pagination parameters in the query string
+#[derive(FromForm, Debug)]
+struct PageForm {
+    limit: Option<u32>,
+    cursor: Option<u32>,
+}
+
+/// The requested page of a list endpoint
+///
+/// Other query parameters are ignored and kept for the link to the next page.
+#[derive(Debug, Clone)]
+pub struct PageQuery {
+    page: PageRequest,
+    path: String,
+    other_params: Vec<String>,
+}
+
+impl PageQuery {
+    /// What to ask the controller for
+    ///
+    /// One more item than the limit is requested, to know whether there is a
+    /// next page.
+    pub fn request(&self) -> PageRequest {
+        PageRequest {
+            offset: self.page.offset,
+            limit: self.page.limit + 1,
+        }
+    }
+
+    /// Cut the items returned for `request` down to this page, together with
+    /// the link to the next page if there are more items
+    pub fn split<T>(&self, mut items: Vec<T>) -> (Vec<T>, Option<String>) {
+        let limit = self.page.limit;
+        if items.len() <= limit as usize {
+            return (items, None);
+        }
+        items.truncate(limit as usize);
+
+        let mut params = self.other_params.clone();
+        params.push(format!("limit={}", limit));
+        params.push(format!("cursor={}", self.page.offset + limit));
+        (items, Some(format!("{}?{}", self.path, params.join("&"))))
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for PageQuery {
+    type Error = ContentError;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<PageQuery, ContentError> {
+        let query = req.uri().query().unwrap_or("");
+        let form = match PageForm::from_form(&mut FormItems::from(query), false) {
+            Ok(form) => form,
+            Err(_) => {
+                info!("Request with invalid pagination parameters: '{}'", query);
+                return Outcome::Failure((Status::BadRequest, ContentError::InvalidContent));
+            }
+        };
+
+        let limit = form.limit.unwrap_or(*DEFAULT_LIMIT).max(1).min(*MAX_LIMIT);
+        let other_params = FormItems::from(query)
+            .filter(|(key, _)| key.as_str() != "limit" && key.as_str() != "cursor")
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+
+        Outcome::Success(PageQuery {
+            page: PageRequest {
+                offset: form.cursor.unwrap_or(0),
+                limit,
+            },
+            path: req.uri().path().to_string(),
+            other_params,
+        })
+    }
+}
+
+/// A response with a link to the next page
+pub struct Paged<R> {
+    pub inner: R,
+    pub next: Option<String>,
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Paged<R> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let mut res = self.inner.respond_to(req)?;
+        if let Some(next) = self.next {
+            res.set_header(Header::new("Link", format!("<{}>; rel=\"next\"", next)));
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,141 @@
+//! Password policy enforced before a password reaches the auth service.
+//!
+//! New passwords must be at least `PASSWORD_MIN_LENGTH` characters long
+//! (default 10), contain at least `PASSWORD_MIN_CLASSES` of lowercase letters,
+//! uppercase letters, digits and other characters (default 2), and must not
+//! contain the username or the local part of the email.
+//!
+//! If `BREACHED_PASSWORDS_DIR` is set, passwords are also looked up in a local
+//! copy of a breached password list. The directory is indexed by the first
+//! five hex digits of the SHA-1 digest of a password: the file `<PREFIX>`
+//! contains one `<SUFFIX>:<COUNT>` line per breached password, which is the
+//! format of the "Pwned Passwords" range files.
+use sha1::{Digest, Sha1};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use crate::crypto;
+use crate::error::GateError;
+
+lazy_static! {
+    static ref MIN_LENGTH: usize = env_number("PASSWORD_MIN_LENGTH", 10);
+    static ref MIN_CLASSES: usize = env_number("PASSWORD_MIN_CLASSES", 2);
+    static ref BREACHED_PASSWORDS_DIR: Option<PathBuf> =
+        match std::env::var("BREACHED_PASSWORDS_DIR") {
+            Ok(value) => Some(PathBuf::from(value)),
+            Err(_) => {
+                warn!("BREACHED_PASSWORDS_DIR is not set, not checking for breached passwords");
+                None
+            }
+        };
+}
+
+fn env_number(name: &str, default: usize) -> usize {
+    match std::env::var(name).map(|value| value.parse::<usize>()) {
+        Ok(Ok(value)) => value,
+        Ok(Err(_)) => {
+            warn!("{} is not a number, using '{}'", name, default);
+            default
+        }
+        Err(_) => {
+            warn!("{} is not set, using '{}'", name, default);
+            default
+        }
+    }
+}
+
+/// Why a password was rejected
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PasswordRejection {
+    TooShort { min_length: usize },
+    TooFewCharacterClasses { min_classes: usize },
+    ContainsUsername,
+    ContainsEmail,
+    /// The password is known from a data breach
+    Breached,
+}
+
+/// Number of character classes used in `password`
+fn character_classes(password: &str) -> usize {
+    let lower = password.chars().any(char::is_lowercase);
+    let upper = password.chars().any(char::is_uppercase);
+    let digit = password.chars().any(|c| c.is_ascii_digit());
+    let other = password.chars().any(|c| !c.is_alphanumeric());
+    [lower, upper, digit, other].iter().filter(|&&used| used).count()
+}
+
+/// Whether `password` is in the breached password list
+fn is_breached(password: &str) -> bool {
+    let dir = match &*BREACHED_PASSWORDS_DIR {
+        Some(dir) => dir,
+        None => return false,
+    };
+
+    let hash = crypto::to_hex(&Sha1::digest(password.as_bytes())).to_uppercase();
+    let (prefix, suffix) = hash.split_at(5);
+
+    let file = match std::fs::File::open(dir.join(prefix)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return false,
+        Err(e) => {
+            // Do not lock everybody out because of a broken list
+            error!("Unable to read breached passwords for '{}': {}", prefix, e);
+            return false;
+        }
+    };
+
+    BufReader::new(file)
+        .lines()
+        .filter_map(Result::ok)
+        .any(|line| line.split(':').next().map_or(false, |s| s.trim() == suffix))
+}
+
+/// Check a new password against the policy
+///
+/// `username` and `email` are checked if they are known.
+pub fn check(
+    password: &str,
+    username: Option<&str>,
+    email: Option<&str>,
+) -> Result<(), PasswordRejection> {
+    if password.chars().count() < *MIN_LENGTH {
+        return Err(PasswordRejection::TooShort {
+            min_length: *MIN_LENGTH,
+        });
+    }
+    if character_classes(password) < *MIN_CLASSES {
+        return Err(PasswordRejection::TooFewCharacterClasses {
+            min_classes: *MIN_CLASSES,
+        });
+    }
+
+    let lowercase = password.to_lowercase();
+    if let Some(username) = username {
+        if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+            return Err(PasswordRejection::ContainsUsername);
+        }
+    }
+    if let Some(local) = email.and_then(|email| email.split('@').next()) {
+        if !local.is_empty() && lowercase.contains(&local.to_lowercase()) {
+            return Err(PasswordRejection::ContainsEmail);
+        }
+    }
+
+    if is_breached(password) {
+        return Err(PasswordRejection::Breached);
+    }
+    Ok(())
+}
+
+/// `check` for routes, logging the rejection
+pub fn verify(
+    password: &str,
+    username: Option<&str>,
+    email: Option<&str>,
+) -> Result<(), GateError> {
+    check(password, username, email).map_err(|rejection| {
+        info!("Rejected password: {:?}", rejection);
+        GateError::PasswordRejected(rejection)
+    })
+}
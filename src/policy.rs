@@ -0,0 +1,364 @@
+//! Declarative permission policy for content and admin requests.
+//!
+//! Every `ContentRequest`, `AdminRequest` and `ModeratorRequest` variant is an
+//! `Action` with a `Rule` saying which role is needed, which role is needed to
+//! act on the content of other users, and whether an API key is enough. Within
+//! a category, users with a moderator grant for it count as moderators, see
+//! `moderators`. The compiled-in defaults can be overridden per action in
+//! `POLICY_FILE`, one rule per line:
+//!
+//! ```text
+//! # action: key=value ...
+//! hide_category: role=moderator
+//! edit_thread: role=user others=admin
+//! add_thread: role=user session_only=true
+//! ```
+//!
+//! Keys which are left out keep their default. Admin actions like `ban_ip`
+//! can not be allowed to roles below admin, such a policy file is refused.
+//! The effective policy is shown by `security-gate policy` and
+//! `GET /api/admin/policy`.
+use rocket_contrib::Json;
+use std::collections::BTreeMap;
+
+use datatypes::auth::responses::Role;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::{CategoryId, UserId};
+
+use crate::comms::auth::ApiScope;
+use crate::error::ApiResult;
+use crate::guards::{Admin, AuthenticatedUser};
+use crate::moderators;
+
+lazy_static! {
+    static ref POLICY: Result<BTreeMap<Action, Rule>, String> =
+        match std::env::var("POLICY_FILE") {
+            Ok(path) => load(&path),
+            Err(_) => {
+                warn!("POLICY_FILE is not set, using the default policy");
+                Ok(defaults())
+            }
+        };
+}
+
+/// Everything a user may request through `post_content`, `post_admin` or
+/// `post_moderators`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Action {
+    AddCategory,
+    EditCategory,
+    HideCategory,
+    AddThread,
+    EditThread,
+    HideThread,
+    AddComment,
+    EditComment,
+    HideComment,
+    EditUser,
+    BanIp,
+    UnbanIp,
+    SetUserRole,
+    GrantModerator,
+    RevokeModerator,
+}
+
+impl Action {
+    const ALL: [Action; 15] = [
+        Action::AddCategory,
+        Action::EditCategory,
+        Action::HideCategory,
+        Action::AddThread,
+        Action::EditThread,
+        Action::HideThread,
+        Action::AddComment,
+        Action::EditComment,
+        Action::HideComment,
+        Action::EditUser,
+        Action::BanIp,
+        Action::UnbanIp,
+        Action::SetUserRole,
+        Action::GrantModerator,
+        Action::RevokeModerator,
+    ];
+
+    /// The name used in `POLICY_FILE`
+    pub fn name(self) -> &'static str {
+        use self::Action::*;
+        match self {
+            AddCategory => "add_category",
+            EditCategory => "edit_category",
+            HideCategory => "hide_category",
+            AddThread => "add_thread",
+            EditThread => "edit_thread",
+            HideThread => "hide_thread",
+            AddComment => "add_comment",
+            EditComment => "edit_comment",
+            HideComment => "hide_comment",
+            EditUser => "edit_user",
+            BanIp => "ban_ip",
+            UnbanIp => "unban_ip",
+            SetUserRole => "set_user_role",
+            GrantModerator => "grant_moderator",
+            RevokeModerator => "revoke_moderator",
+        }
+    }
+
+    /// Whether the action is reserved to admins, whatever the policy file says
+    fn admin_only(self) -> bool {
+        use self::Action::*;
+        match self {
+            BanIp | UnbanIp | SetUserRole | GrantModerator | RevokeModerator => true,
+            _ => false,
+        }
+    }
+}
+
+/// Who may perform an action
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    /// The role needed to perform the action at all
+    pub role: Role,
+    /// The role needed to act on the content of other users. `None` if the
+    /// action does not target content of a user.
+    pub others: Option<Role>,
+    /// Whether the action needs a session, i.e. API keys are not enough
+    pub session_only: bool,
+}
+
+impl Rule {
+    fn new(role: Role) -> Rule {
+        Rule {
+            role,
+            others: None,
+            session_only: false,
+        }
+    }
+
+    fn others(self, others: Role) -> Rule {
+        Rule {
+            others: Some(others),
+            ..self
+        }
+    }
+
+    fn session_only(self) -> Rule {
+        Rule {
+            session_only: true,
+            ..self
+        }
+    }
+}
+
+/// The compiled-in policy
+fn defaults() -> BTreeMap<Action, Rule> {
+    use self::Action::*;
+    let mut policy = BTreeMap::new();
+    policy.insert(AddCategory, Rule::new(Role::Moderator).session_only());
+    policy.insert(EditCategory, Rule::new(Role::Moderator));
+    policy.insert(HideCategory, Rule::new(Role::Admin));
+    policy.insert(AddThread, Rule::new(Role::User));
+    policy.insert(EditThread, Rule::new(Role::User).others(Role::Moderator));
+    policy.insert(HideThread, Rule::new(Role::User).others(Role::Moderator));
+    policy.insert(AddComment, Rule::new(Role::User));
+    policy.insert(EditComment, Rule::new(Role::User).others(Role::Moderator));
+    policy.insert(HideComment, Rule::new(Role::User).others(Role::Moderator));
+    policy.insert(EditUser, Rule::new(Role::User).session_only());
+    policy.insert(BanIp, Rule::new(Role::Admin).session_only());
+    policy.insert(UnbanIp, Rule::new(Role::Admin).session_only());
+    policy.insert(SetUserRole, Rule::new(Role::Admin).session_only());
+    policy.insert(GrantModerator, Rule::new(Role::Admin).session_only());
+    policy.insert(RevokeModerator, Rule::new(Role::Admin).session_only());
+    policy
+}
+
+/// Parse a role as written in `POLICY_FILE` and on the command line
+pub fn parse_role(value: &str) -> Result<Role, String> {
+    match value {
+        "user" => Ok(Role::User),
+        "moderator" => Ok(Role::Moderator),
+        "admin" => Ok(Role::Admin),
+        _ => Err(format!("Unknown role '{}'", value)),
+    }
+}
+
+/// The name of a role as written in `POLICY_FILE`
+pub fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::User => "user",
+        Role::Moderator => "moderator",
+        Role::Admin => "admin",
+    }
+}
+
+/// Apply the overrides in `path` to the default policy
+fn load(path: &str) -> Result<BTreeMap<Action, Rule>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read policy file '{}': {}", path, e))?;
+    let mut policy = defaults();
+
+    for (number, line) in content.lines().map(str::trim).enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: &str| format!("{}:{}: {}", path, number + 1, reason);
+
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let action = Action::ALL
+            .iter()
+            .find(|action| action.name() == name)
+            .ok_or_else(|| error(&format!("unknown action '{}'", name)))?;
+        let rule = policy
+            .get_mut(action)
+            .ok_or_else(|| error("action without default"))?;
+
+        for setting in parts.next().unwrap_or("").split_whitespace() {
+            let mut kv = setting.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("role"), Some(value)) => {
+                    rule.role = parse_role(value).map_err(|e| error(&e))?
+                }
+                (Some("others"), Some(value)) => {
+                    rule.others = Some(parse_role(value).map_err(|e| error(&e))?)
+                }
+                (Some("session_only"), Some(value)) => {
+                    rule.session_only = value
+                        .parse::<bool>()
+                        .map_err(|_| error("session_only must be 'true' or 'false'"))?
+                }
+                _ => return Err(error(&format!("invalid setting '{}'", setting))),
+            }
+        }
+        if action.admin_only() && rule.role < Role::Admin {
+            return Err(error(&format!("'{}' can only be allowed to admins", name)));
+        }
+    }
+
+    info!("Loaded policy from '{}'", path);
+    Ok(policy)
+}
+
+/// The effective policy, or why `POLICY_FILE` could not be loaded
+pub fn rules() -> Result<&'static BTreeMap<Action, Rule>, String> {
+    POLICY.as_ref().map_err(Clone::clone)
+}
+
+/// A policy in the format of `POLICY_FILE`
+pub fn describe(policy: &BTreeMap<Action, Rule>) -> String {
+    policy
+        .iter()
+        .map(|(action, rule)| {
+            let mut line = format!("{}: role={}", action.name(), role_name(rule.role));
+            if let Some(others) = rule.others {
+                line.push_str(&format!(" others={}", role_name(others)));
+            }
+            line.push_str(&format!(" session_only={}", rule.session_only));
+            line
+        }).collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The category, and the owner of the content, an action targets
+#[derive(Debug, Clone, Copy)]
+pub struct Target {
+    pub category: CategoryId,
+    /// `None` if the action targets the category itself
+    pub author: Option<UserId>,
+}
+
+/// Fail with `Unauthorized` unless `role`, the role of `user` in the targeted
+/// category, is at least `needed`
+fn require(user: &AuthenticatedUser, role: Role, needed: Role) -> Result<(), ResponseError> {
+    if role < needed {
+        warn!(
+            "User ({:?}) with role {:?} tried to perform an action requiring {:?}",
+            user.id, role, needed
+        );
+        return Err(ResponseError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Check whether `user` may perform `action`, within `target` if it targets a
+/// category or content in one
+///
+/// Acting on a category, or on content of other users in it, through an API
+/// key additionally needs the `ModerateCategory` scope of the category.
+pub fn authorize(
+    action: Action,
+    user: &AuthenticatedUser,
+    target: Option<Target>,
+) -> Result<(), ResponseError> {
+    let rule = rules()
+        .map_err(|e| {
+            error!("Invalid permission policy: {}", e);
+            ResponseError::InternalServerError
+        })?.get(&action)
+        .ok_or(ResponseError::InternalServerError)?;
+
+    if rule.session_only {
+        user.session_token()?;
+    }
+    // Moderator grants only need to be looked up if the global role is too low
+    let needed = match (target, rule.others) {
+        (Some(Target { author: Some(author), .. }), Some(others))
+            if author != user.id && others > rule.role =>
+        {
+            others
+        }
+        _ => rule.role,
+    };
+    let role = match target {
+        Some(target) if user.role < needed => {
+            moderators::role_in(user, target.category)?
+        }
+        _ => user.role,
+    };
+    require(user, role, rule.role)?;
+
+    match target {
+        Some(Target {
+            category,
+            author: None,
+        }) => user.require_scope(&ApiScope::ModerateCategory(category))?,
+        Some(Target {
+            category,
+            author: Some(author),
+        }) if author != user.id =>
+        {
+            match rule.others {
+                Some(others) => require(user, role, others)?,
+                None => return Err(ResponseError::Unauthorized),
+            }
+            user.require_scope(&ApiScope::ModerateCategory(category))?;
+            info!(
+                "User ({:?}) with role {:?} performing {:?} on content of user ({:?})",
+                user.id, role, action, author
+            );
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Get the effective permission policy
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "ADD_CATEGORY": { "role": "MODERATOR", "others": null, "session_only": true },
+///     "EDIT_THREAD": { "role": "USER", "others": "MODERATOR", "session_only": false },
+///     ...
+/// }
+/// ´´´
+#[get("/admin/policy")]
+pub fn get_policy(_admin: Admin) -> ApiResult<BTreeMap<Action, Rule>> {
+    let policy = rules().map_err(|e| {
+        error!("Invalid permission policy: {}", e);
+        ResponseError::InternalServerError
+    })?;
+    info!("Returning success from 'get-policy' request");
+    Ok(Json(policy.clone()))
+}
@@ -0,0 +1,324 @@
+//! Optional hashcash-style proof-of-work for registration and login.
+//!
+//! When enabled with `POW_ENABLED=true`, clients have to fetch a challenge
+//! from `/api/auth/challenge` and find a nonce such that
+//! `SHA-256("<challenge>:<nonce>")` starts with at least `difficulty` zero
+//! bits. The solution is sent as `X-Proof-Of-Work: <challenge>:<nonce>`
+//! together with the `AUTHENTICATE` or `REGISTER_USER` request.
+//!
+//! Each challenge can only be used once, and only by the client IP it was
+//! issued to. The difficulty starts at `POW_DIFFICULTY` and grows by one bit
+//! every time the number of challenges the same client has outstanding
+//! doubles. It also grows by one bit every time the number of challenges
+//! outstanding overall doubles beyond `RAMP_THRESHOLD`, so clients rotating
+//! their addresses are slowed down as well. It never exceeds
+//! `POW_MAX_DIFFICULTY`.
+//!
+//! A client keeps at most `MAX_PER_CLIENT` challenges, and at most
+//! `MAX_OUTSTANDING` are kept overall. When either is reached, the oldest
+//! challenge is dropped to make room for the new one. Challenges are kept in
+//! the order they were issued, which is the order they expire in, so expired
+//! challenges are dropped without looking at the others.
+use chrono::prelude::*;
+use chrono::Duration;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+use rocket_contrib::Json;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+
+use datatypes::error::ResponseError;
+
+use crate::crypto;
+use crate::error::{ApiResult, GateError};
+
+/// Upper bound on outstanding challenges of a single client
+const MAX_PER_CLIENT: usize = 16;
+/// Upper bound on outstanding challenges kept in memory
+const MAX_OUTSTANDING: usize = 100_000;
+/// Outstanding challenges overall from which the difficulty grows
+const RAMP_THRESHOLD: usize = 1000;
+/// How long a challenge can be used
+const LIFETIME_MINUTES: i64 = 2;
+
+lazy_static! {
+    static ref ENABLED: bool = match std::env::var("POW_ENABLED") {
+        Ok(value) => value == "true" || value == "1",
+        Err(_) => {
+            warn!("POW_ENABLED is not set, using 'false'");
+            false
+        }
+    };
+    static ref BASE_DIFFICULTY: u32 = env_number("POW_DIFFICULTY", 16);
+    static ref MAX_DIFFICULTY: u32 = env_number("POW_MAX_DIFFICULTY", 24);
+    static ref CHALLENGES: RwLock<Challenges> = RwLock::new(Challenges::default());
+}
+
+fn env_number(name: &str, default: u32) -> u32 {
+    match std::env::var(name).map(|value| value.parse::<u32>()) {
+        Ok(Ok(value)) => value,
+        Ok(Err(_)) => {
+            warn!("{} is not a number, using '{}'", name, default);
+            default
+        }
+        Err(_) => {
+            warn!("{} is not set, using '{}'", name, default);
+            default
+        }
+    }
+}
+
+/// An issued challenge
+struct Challenge {
+    /// The client the challenge was issued to, if its address is known
+    client: Option<IpAddr>,
+    difficulty: u32,
+    expires: DateTime<Utc>,
+}
+
+/// The outstanding challenges
+#[derive(Default)]
+struct Challenges {
+    by_key: HashMap<String, Challenge>,
+    /// Keys in the order they were issued. Keys of challenges which were
+    /// used already are skipped when they come up.
+    order: VecDeque<String>,
+    /// Keys of the challenges of each client in the order they were issued
+    by_client: HashMap<Option<IpAddr>, VecDeque<String>>,
+}
+
+impl Challenges {
+    fn remove(&mut self, key: &str) -> Option<Challenge> {
+        let challenge = self.by_key.remove(key)?;
+        let emptied = match self.by_client.get_mut(&challenge.client) {
+            Some(keys) => {
+                keys.retain(|k| k != key);
+                keys.is_empty()
+            }
+            None => false,
+        };
+        if emptied {
+            self.by_client.remove(&challenge.client);
+        }
+        Some(challenge)
+    }
+
+    /// Drop the oldest challenge, and keys of used challenges before it
+    fn evict_oldest(&mut self) {
+        while let Some(key) = self.order.pop_front() {
+            if self.remove(&key).is_some() {
+                return;
+            }
+        }
+    }
+
+    /// Drop expired challenges, which are all at the front
+    fn prune(&mut self, now: DateTime<Utc>) {
+        while let Some(key) = self.order.front().cloned() {
+            let expired = self.by_key.get(&key).map_or(true, |c| c.expires <= now);
+            if !expired {
+                return;
+            }
+            self.order.pop_front();
+            self.remove(&key);
+        }
+    }
+
+    fn outstanding(&self, client: Option<IpAddr>) -> usize {
+        self.by_client.get(&client).map_or(0, VecDeque::len)
+    }
+
+    fn insert(&mut self, key: String, challenge: Challenge) {
+        self.by_client
+            .entry(challenge.client)
+            .or_insert_with(VecDeque::new)
+            .push_back(key.clone());
+        self.order.push_back(key.clone());
+        self.by_key.insert(key, challenge);
+
+        // Keys of used challenges pile up behind challenges which are still
+        // valid, drop them once they outnumber the valid ones
+        if self.order.len() > 2 * MAX_OUTSTANDING {
+            let by_key = &self.by_key;
+            self.order.retain(|key| by_key.contains_key(key));
+        }
+    }
+}
+
+/// Number of times `count` has to be halved to reach zero
+fn doublings(count: usize) -> u32 {
+    let mut doublings = 0;
+    let mut count = count;
+    while count > 0 {
+        doublings += 1;
+        count /= 2;
+    }
+    doublings
+}
+
+/// The difficulty of a new challenge given the number of challenges the
+/// client has outstanding, and the number outstanding overall
+fn difficulty(client_outstanding: usize, outstanding: usize) -> u32 {
+    let extra = doublings(client_outstanding) + doublings(outstanding / RAMP_THRESHOLD);
+    (*BASE_DIFFICULTY + extra).min(*MAX_DIFFICULTY)
+}
+
+/// Number of leading zero bits in `hash`
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+/// A solution sent as `X-Proof-Of-Work: <challenge>:<nonce>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofOfWork {
+    pub challenge: String,
+    pub nonce: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ProofOfWork {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<ProofOfWork, ()> {
+        let value = match req.headers().get_one("X-Proof-Of-Work") {
+            Some(value) => value,
+            None => return Outcome::Forward(()),
+        };
+
+        let mut parts = value.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(challenge), Some(nonce)) => Outcome::Success(ProofOfWork {
+                challenge: challenge.to_string(),
+                nonce: nonce.to_string(),
+            }),
+            _ => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Check the proof-of-work of a request from `remote`, if proof-of-work is
+/// enabled
+///
+/// The challenge is consumed even if the solution is wrong.
+pub fn verify(proof: Option<ProofOfWork>, remote: Option<SocketAddr>) -> Result<(), GateError> {
+    if !*ENABLED {
+        return Ok(());
+    }
+
+    let proof = proof.ok_or(GateError::ProofOfWorkRequired)?;
+    let client = remote.map(|addr| addr.ip());
+    let challenge = CHALLENGES
+        .write()
+        .map_err(|e| {
+            error!("Error writing to proof-of-work challenges: {}", e);
+            GateError::InvalidProofOfWork
+        })?.remove(&proof.challenge)
+        .filter(|c| c.expires > Utc::now())
+        .ok_or(GateError::InvalidProofOfWork)?;
+
+    if challenge.client != client {
+        info!("Rejected proof-of-work for a challenge of another client");
+        return Err(GateError::InvalidProofOfWork);
+    }
+
+    let hash = Sha256::digest(format!("{}:{}", proof.challenge, proof.nonce).as_bytes());
+    if leading_zero_bits(&hash) < challenge.difficulty {
+        info!("Rejected proof-of-work with too little work");
+        return Err(GateError::InvalidProofOfWork);
+    }
+    Ok(())
+}
+
+/// A challenge which has to be solved before registering or logging in
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PowChallengePayload {
+    pub challenge: String,
+    pub difficulty: u32,
+}
+
+/// The outcome of a successful challenge request
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PowSuccess {
+    Challenge(PowChallengePayload),
+    /// Proof-of-work is disabled, no challenge has to be solved
+    NoChallenge,
+}
+
+/// Get a proof-of-work challenge
+///
+/// # Example
+///
+/// ## Query
+///
+/// ´´´text
+/// localhost:9234/api/auth/challenge
+/// ´´´
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "type": "CHALLENGE",
+///     "payload": {
+///         "challenge": "9c1185a5c5e9fc54612808977ee8f548",
+///         "difficulty": 16
+///     }
+/// }
+/// ´´´
+#[get("/auth/challenge")]
+pub fn get_challenge(remote: Option<SocketAddr>) -> ApiResult<PowSuccess> {
+    if !*ENABLED {
+        return Ok(Json(PowSuccess::NoChallenge));
+    }
+
+    let client = remote.map(|addr| addr.ip());
+    let mut challenges = CHALLENGES
+        .write()
+        .map_err(|_| ResponseError::InternalServerError)?;
+
+    let now = Utc::now();
+    challenges.prune(now);
+
+    if challenges.outstanding(client) >= MAX_PER_CLIENT {
+        debug!("Client has too many proof-of-work challenges, dropping the oldest");
+        let oldest = challenges
+            .by_client
+            .get(&client)
+            .and_then(|keys| keys.front().cloned());
+        if let Some(key) = oldest {
+            challenges.remove(&key);
+        }
+    }
+    if challenges.by_key.len() >= MAX_OUTSTANDING {
+        warn!("Too many outstanding proof-of-work challenges, dropping the oldest");
+        challenges.evict_oldest();
+    }
+
+    let challenge = crypto::random_hex(16);
+    let difficulty = difficulty(challenges.outstanding(client), challenges.by_key.len());
+    challenges.insert(
+        challenge.clone(),
+        Challenge {
+            client,
+            difficulty,
+            expires: now + Duration::minutes(LIFETIME_MINUTES),
+        },
+    );
+
+    debug!("Issued proof-of-work challenge with difficulty {}", difficulty);
+    Ok(Json(PowSuccess::Challenge(PowChallengePayload {
+        challenge,
+        difficulty,
+    })))
+}
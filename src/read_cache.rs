@@ -0,0 +1,455 @@
+//! In-process cache of public content reads.
+//!
+//! Anonymous visitors mostly read the same categories, thread listings and
+//! threads, and every read costs a fresh connection to the controller. Reads
+//! without hidden content are therefore kept for `READ_CACHE_TTL` seconds
+//! (default 10, `0` disables the cache). At most `READ_CACHE_MAX_ENTRIES`
+//! (default 1000) reads are kept, the least recently used are evicted first.
+//!
+//! Every entry carries tags naming what it depends on, e.g. a category. When
+//! `post_content` changes content, the entries with a matching tag are
+//! invalidated. A read which started before such an invalidation may have
+//! loaded the old content, so it is not cached. Hits, misses, evictions and
+//! invalidations are counted and shown by `GET /api/admin/cache`.
+use chrono::prelude::*;
+use chrono::Duration;
+use rocket_contrib::Json;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use datatypes::valid::ids::{CategoryId, ThreadId};
+
+use crate::error::ApiResult;
+use crate::guards::Admin;
+
+lazy_static! {
+    pub static ref READ_CACHE: ReadCache = {
+        let ttl = match std::env::var("READ_CACHE_TTL") {
+            Ok(value) => value.parse::<i64>().unwrap_or_else(|_| {
+                warn!("READ_CACHE_TTL is not a number, using '10'");
+                10
+            }),
+            Err(_) => {
+                warn!("READ_CACHE_TTL is not set, using '10'");
+                10
+            }
+        };
+        let max_entries = match std::env::var("READ_CACHE_MAX_ENTRIES") {
+            Ok(value) => value.parse::<usize>().unwrap_or_else(|_| {
+                warn!("READ_CACHE_MAX_ENTRIES is not a number, using '1000'");
+                1000
+            }),
+            Err(_) => {
+                warn!("READ_CACHE_MAX_ENTRIES is not set, using '1000'");
+                1000
+            }
+        };
+        ReadCache::new(Duration::seconds(ttl), max_entries)
+    };
+}
+
+/// Tag of the category listing
+pub const CATEGORIES: &str = "categories";
+/// Tag of all thread listings
+pub const THREADS: &str = "threads";
+
+/// Tag of everything depending on the category `id`
+pub fn category_tag(id: CategoryId) -> String {
+    format!("category:{:?}", id)
+}
+
+/// Tag of everything depending on the thread `id`
+pub fn thread_tag(id: ThreadId) -> String {
+    format!("thread:{:?}", id)
+}
+
+struct Entry {
+    value: Arc<dyn Any + Send + Sync>,
+    tags: Vec<String>,
+    expires: DateTime<Utc>,
+    /// Position in `Entries::order`
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    /// Keys by when they were last used, the least recently used first
+    order: BTreeMap<u64, String>,
+    clock: u64,
+    /// Counts invalidations
+    generation: u64,
+    /// The generation of the last invalidation of each tag
+    invalidated: HashMap<String, u64>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.by_key.remove(key)?;
+        self.order.remove(&entry.used);
+        Some(entry)
+    }
+
+    /// Whether any of `tags` was invalidated after `generation`
+    fn invalidated_since(&self, tags: &[String], generation: u64) -> bool {
+        tags.iter()
+            .any(|tag| self.invalidated.get(tag).map_or(false, |g| *g > generation))
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.by_key.get_mut(key) {
+            self.order.remove(&entry.used);
+            entry.used = clock;
+            self.order.insert(clock, key.to_string());
+        }
+    }
+}
+
+/// Counters of the cache since the gate started
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CacheMetrics {
+    pub entries: usize,
+    pub max_entries: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub invalidations: usize,
+}
+
+pub struct ReadCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    invalidations: AtomicUsize,
+}
+
+impl ReadCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> ReadCache {
+        ReadCache {
+            ttl,
+            max_entries,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+            invalidations: AtomicUsize::new(0),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.ttl > Duration::zero() && self.max_entries > 0
+    }
+
+    /// Look up `key`, counting the lookup as a hit or a miss
+    pub fn get<T: Clone + 'static>(&self, key: &str) -> Option<T> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let found = match self.entries.lock() {
+            Ok(mut entries) => {
+                let found = entries
+                    .by_key
+                    .get(key)
+                    .filter(|entry| entry.expires > Utc::now())
+                    .and_then(|entry| entry.value.downcast_ref::<T>().cloned());
+                if found.is_some() {
+                    entries.touch(key);
+                }
+                found
+            }
+            Err(e) => {
+                error!("Error reading read cache: {}", e);
+                None
+            }
+        };
+
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        trace!("Read cache: {} hits, {} misses", self.hits(), self.misses());
+        found
+    }
+
+    /// Keep `value` under `key`, evicting the least recently used entries if
+    /// the cache is full
+    pub fn insert<T: Send + Sync + 'static>(&self, key: String, tags: Vec<String>, value: T) {
+        self.insert_since(key, tags, value, None)
+    }
+
+    /// The current generation, to be passed to `insert_since` for a value
+    /// which is loaded afterwards
+    fn generation(&self) -> Option<u64> {
+        match self.entries.lock() {
+            Ok(entries) => Some(entries.generation),
+            Err(e) => {
+                error!("Error reading read cache: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Like `insert`, but nothing is kept if any of `tags` was invalidated
+    /// after `generation`, as `value` may be stale then
+    fn insert_since<T: Send + Sync + 'static>(
+        &self,
+        key: String,
+        tags: Vec<String>,
+        value: T,
+        generation: Option<u64>,
+    ) {
+        if !self.enabled() {
+            return;
+        }
+
+        let now = Utc::now();
+        match self.entries.lock() {
+            Ok(mut entries) => {
+                if let Some(generation) = generation {
+                    if entries.invalidated_since(&tags, generation) {
+                        debug!("Not caching '{}', it was invalidated while loading", key);
+                        return;
+                    }
+                }
+                entries.remove(&key);
+
+                // Expired entries go first, then the least recently used
+                let expired: Vec<String> = entries
+                    .by_key
+                    .iter()
+                    .filter(|(_, entry)| entry.expires <= now)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in expired {
+                    entries.remove(&key);
+                }
+                while entries.by_key.len() >= self.max_entries {
+                    let oldest = match entries.order.values().next() {
+                        Some(key) => key.clone(),
+                        None => break,
+                    };
+                    entries.remove(&oldest);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+
+                entries.by_key.insert(
+                    key.clone(),
+                    Entry {
+                        value: Arc::new(value),
+                        tags,
+                        expires: now + self.ttl,
+                        used: 0,
+                    },
+                );
+                entries.touch(&key);
+            }
+            Err(e) => error!("Error writing to read cache: {}", e),
+        }
+    }
+
+    /// Look up `key`, or load and keep the value if it is not cached
+    ///
+    /// The tags of a loaded value are given by `tags`. Nothing is cached if
+    /// `key` is `None`, e.g. for reads including hidden content, or if one of
+    /// the tags was invalidated while the value was loaded.
+    pub fn get_or_load<T, E, F, G>(
+        &self,
+        key: Option<String>,
+        tags: G,
+        load: F,
+    ) -> Result<T, E>
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce() -> Result<T, E>,
+        G: FnOnce(&T) -> Vec<String>,
+    {
+        let key = match key {
+            Some(key) => key,
+            None => return load(),
+        };
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+
+        let generation = match self.generation() {
+            Some(generation) => generation,
+            None => return load(),
+        };
+        let value = load()?;
+        self.insert_since(key, tags(&value), value.clone(), Some(generation));
+        Ok(value)
+    }
+
+    /// Forget all entries tagged with `tag`, e.g. when the content changes
+    ///
+    /// Values of `tag` which are being loaded right now are not kept either.
+    pub fn invalidate(&self, tag: &str) {
+        match self.entries.lock() {
+            Ok(mut entries) => {
+                entries.generation += 1;
+                let generation = entries.generation;
+                entries.invalidated.insert(tag.to_string(), generation);
+
+                let keys: Vec<String> = entries
+                    .by_key
+                    .iter()
+                    .filter(|(_, entry)| entry.tags.iter().any(|t| t == tag))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in &keys {
+                    entries.remove(key);
+                }
+                self.invalidations.fetch_add(keys.len(), Ordering::Relaxed);
+                debug!("Invalidated {} cached reads tagged '{}'", keys.len(), tag);
+            }
+            Err(e) => error!("Error writing to read cache: {}", e),
+        }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        let entries = match self.entries.lock() {
+            Ok(entries) => entries.by_key.len(),
+            Err(_) => 0,
+        };
+        CacheMetrics {
+            entries,
+            max_entries: self.max_entries,
+            hits: self.hits(),
+            misses: self.misses(),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Get the counters of the read cache
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "entries": 212,
+///     "max_entries": 1000,
+///     "hits": 48213,
+///     "misses": 3120,
+///     "evictions": 0,
+///     "invalidations": 87
+/// }
+/// ´´´
+#[get("/admin/cache")]
+pub fn get_cache_metrics(_admin: Admin) -> ApiResult<CacheMetrics> {
+    info!("Returning success from 'get-cache-metrics' request");
+    Ok(Json(READ_CACHE.metrics()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time;
+
+    fn cache(max_entries: usize) -> ReadCache {
+        ReadCache::new(Duration::seconds(60), max_entries)
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache(2);
+        cache.insert("a".to_string(), vec![], 1);
+        cache.insert("b".to_string(), vec![], 2);
+        assert_eq!(cache.get::<i32>("a"), Some(1));
+
+        cache.insert("c".to_string(), vec![], 3);
+        assert_eq!(cache.get::<i32>("a"), Some(1));
+        assert_eq!(cache.get::<i32>("b"), None);
+        assert_eq!(cache.get::<i32>("c"), Some(3));
+        assert_eq!(cache.metrics().evictions, 1);
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = ReadCache::new(Duration::milliseconds(50), 10);
+        cache.insert("a".to_string(), vec![], 1);
+        assert_eq!(cache.get::<i32>("a"), Some(1));
+
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(cache.get::<i32>("a"), None);
+    }
+
+    #[test]
+    fn disabled_without_ttl() {
+        let cache = ReadCache::new(Duration::zero(), 10);
+        cache.insert("a".to_string(), vec![], 1);
+        assert_eq!(cache.get::<i32>("a"), None);
+    }
+
+    #[test]
+    fn invalidates_tagged_entries() {
+        let cache = cache(10);
+        cache.insert("a".to_string(), tags(&["x", "y"]), 1);
+        cache.insert("b".to_string(), tags(&["y"]), 2);
+        cache.insert("c".to_string(), tags(&["z"]), 3);
+
+        cache.invalidate("y");
+        assert_eq!(cache.get::<i32>("a"), None);
+        assert_eq!(cache.get::<i32>("b"), None);
+        assert_eq!(cache.get::<i32>("c"), Some(3));
+        assert_eq!(cache.metrics().invalidations, 2);
+    }
+
+    #[test]
+    fn skips_values_invalidated_while_loading() {
+        let cache = cache(10);
+        let loaded: Result<i32, ()> = cache.get_or_load(
+            Some("a".to_string()),
+            |_| tags(&["x"]),
+            || {
+                cache.invalidate("x");
+                Ok(1)
+            },
+        );
+        assert_eq!(loaded, Ok(1));
+        assert_eq!(cache.get::<i32>("a"), None);
+
+        let loaded: Result<i32, ()> =
+            cache.get_or_load(Some("a".to_string()), |_| tags(&["x"]), || Ok(2));
+        assert_eq!(loaded, Ok(2));
+        assert_eq!(cache.get::<i32>("a"), Some(2));
+    }
+
+    #[test]
+    fn keeps_values_if_other_tags_are_invalidated() {
+        let cache = cache(10);
+        let loaded: Result<i32, ()> = cache.get_or_load(
+            Some("a".to_string()),
+            |_| tags(&["x"]),
+            || {
+                cache.invalidate("y");
+                Ok(1)
+            },
+        );
+        assert_eq!(loaded, Ok(1));
+        assert_eq!(cache.get::<i32>("a"), Some(1));
+    }
+}
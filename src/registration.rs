@@ -0,0 +1,342 @@
+//! Registration policy: open, closed or invite-only.
+//!
+//! The initial mode is read from `REGISTRATION_MODE` (`open`, `closed` or
+//! `invite`) and can be changed by admins at runtime, e.g. to close signups
+//! during a spam wave. If `REGISTRATION_MODE_FILE` is set, a mode set by an
+//! admin is written to that file and read from it again on startup, taking
+//! precedence over `REGISTRATION_MODE`.
+//!
+//! While invite-only, a `REGISTER_USER` request must carry an invite code in
+//! the `X-Invite-Code` header. Invites are created by admins, can be used a
+//! limited number of times and may expire. One use of the invite is reserved
+//! before the user is registered, and given back if registering fails. The
+//! auth service keeps track of who used which invite.
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+use rocket_contrib::Json;
+use std::sync::RwLock;
+
+use datatypes::content::responses::ContentError;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::UserId;
+
+use crate::auth::connect_to_auth;
+use crate::comms::auth::SyncClient as AuthClient;
+use crate::comms::auth::{CreateInvitePayload, InvitePayload, RedeemInvitePayload};
+use crate::crypto;
+use crate::error::{ApiError, ApiResult, GateError};
+use crate::guards::Admin;
+
+lazy_static! {
+    static ref MODE_FILE: Option<String> = match std::env::var("REGISTRATION_MODE_FILE") {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("REGISTRATION_MODE_FILE is not set, the mode is kept in memory only");
+            None
+        }
+    };
+    static ref MODE: RwLock<RegistrationMode> = RwLock::new(match load_mode() {
+        Ok(Some(mode)) => mode,
+        Ok(None) => env_mode(),
+        Err(e) => {
+            // Fail closed rather than letting anyone in
+            error!("Unable to load registration mode: {}", e);
+            RegistrationMode::Closed
+        }
+    });
+}
+
+fn parse_mode(value: &str) -> Option<RegistrationMode> {
+    match value {
+        "open" => Some(RegistrationMode::Open),
+        "closed" => Some(RegistrationMode::Closed),
+        "invite" => Some(RegistrationMode::InviteOnly),
+        _ => None,
+    }
+}
+
+fn mode_name(mode: RegistrationMode) -> &'static str {
+    match mode {
+        RegistrationMode::Open => "open",
+        RegistrationMode::Closed => "closed",
+        RegistrationMode::InviteOnly => "invite",
+    }
+}
+
+fn env_mode() -> RegistrationMode {
+    match std::env::var("REGISTRATION_MODE") {
+        Ok(value) => parse_mode(&value).unwrap_or_else(|| {
+            warn!("Unknown REGISTRATION_MODE '{}', using 'open'", value);
+            RegistrationMode::Open
+        }),
+        Err(_) => {
+            warn!("REGISTRATION_MODE is not set, using 'open'");
+            RegistrationMode::Open
+        }
+    }
+}
+
+/// Read the mode from `REGISTRATION_MODE_FILE`
+///
+/// A missing file means that no admin has set the mode yet.
+fn load_mode() -> Result<Option<RegistrationMode>, String> {
+    let path = match &*MODE_FILE {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Unable to read '{}': {}", path, e)),
+    };
+    parse_mode(content.trim())
+        .map(Some)
+        .ok_or_else(|| format!("Invalid registration mode in '{}'", path))
+}
+
+/// Write the mode to `REGISTRATION_MODE_FILE`, if it is set
+fn save_mode(mode: RegistrationMode) -> Result<(), String> {
+    match &*MODE_FILE {
+        Some(path) => std::fs::write(path, format!("{}\n", mode_name(mode)))
+            .map_err(|e| format!("Unable to write '{}': {}", path, e)),
+        None => Ok(()),
+    }
+}
+
+/// Who may register
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RegistrationMode {
+    Open,
+    Closed,
+    InviteOnly,
+}
+
+/// The current registration mode
+pub fn mode() -> RegistrationMode {
+    match MODE.read() {
+        Ok(mode) => *mode,
+        Err(e) => {
+            // Fail closed rather than letting anyone in
+            error!("Error reading registration mode: {}", e);
+            RegistrationMode::Closed
+        }
+    }
+}
+
+/// An invite code sent as `X-Invite-Code`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InviteCode(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for InviteCode {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<InviteCode, ()> {
+        match req.headers().get_one("X-Invite-Code") {
+            Some(value) => Outcome::Success(InviteCode(value.trim().to_string())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// One use of an invite, held until the registration finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation(String);
+
+/// Check whether a registration may go ahead
+///
+/// If registration is invite-only, one use of the invite is reserved. It has
+/// to be redeemed once the user exists, or released if registering fails.
+pub fn reserve(
+    auth: &AuthClient,
+    invite: Option<InviteCode>,
+) -> Result<Option<Reservation>, ApiError> {
+    match mode() {
+        RegistrationMode::Open => Ok(None),
+        RegistrationMode::Closed => {
+            info!("Rejected registration while registration is closed");
+            Err(GateError::RegistrationClosed.into())
+        }
+        RegistrationMode::InviteOnly => {
+            let code = invite.ok_or(GateError::InviteRequired)?.0;
+            // Checking and holding a use happen in one step in the auth
+            // service, so two registrations cannot both take the last use
+            auth.reserve_invite(code)
+                .map(|reservation| Some(Reservation(reservation)))
+                .map_err(|e| {
+                    info!("Rejected registration with unusable invite: {:?}", e);
+                    GateError::InvalidInvite.into()
+                })
+        }
+    }
+}
+
+/// Record that `user_id` registered with the reserved invite use
+pub fn redeem(auth: &AuthClient, reservation: Reservation, user_id: UserId) {
+    let p = RedeemInvitePayload {
+        reservation: reservation.0,
+        user_id,
+    };
+    match auth.redeem_invite(p) {
+        Ok(()) => info!("User ({:?}) registered with an invite", user_id),
+        Err(e) => error!("Unable to 'redeem_invite': {:?}", e),
+    }
+}
+
+/// Give back a reserved invite use after registering failed
+pub fn release(auth: &AuthClient, reservation: Reservation) {
+    match auth.release_invite(reservation.0) {
+        Ok(()) => debug!("Released invite reservation of a failed registration"),
+        Err(e) => error!("Unable to 'release_invite': {:?}", e),
+    }
+}
+
+/// Requests which change the registration policy
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RegistrationRequest {
+    SetRegistrationMode(SetRegistrationModePayload),
+    CreateInvite(CreateInviteForm),
+    RevokeInvite(RevokeInviteForm),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetRegistrationModePayload {
+    pub mode: RegistrationMode,
+}
+
+/// A new invite, usable `max_uses` times (default once) until `expires`
+#[derive(Deserialize, Debug)]
+pub struct CreateInviteForm {
+    pub max_uses: Option<u32>,
+    /// Seconds since the unix epoch
+    pub expires: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RevokeInviteForm {
+    pub code: String,
+}
+
+/// The outcome of a successful registration policy request
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RegistrationSuccess {
+    RegistrationMode(RegistrationMode),
+    Invites(Vec<InvitePayload>),
+    InviteCreated(InvitePayload),
+    InviteRevoked,
+}
+
+/// Get the registration mode
+///
+/// Lets the frontend hide or adapt the signup form.
+///
+/// ´´´json
+/// {
+///     "type": "REGISTRATION_MODE",
+///     "payload": "INVITE_ONLY"
+/// }
+/// ´´´
+#[get("/registration")]
+pub fn get_registration_mode() -> ApiResult<RegistrationSuccess> {
+    Ok(Json(RegistrationSuccess::RegistrationMode(mode())))
+}
+
+/// Get all invites, including who used them
+#[get("/admin/invites")]
+pub fn get_invites(_admin: Admin) -> ApiResult<RegistrationSuccess> {
+    connect_to_auth()?
+        .get_invites(())
+        .map(|v| {
+            info!("Returning success from 'get-invites' request");
+            Json(RegistrationSuccess::Invites(v))
+        }).map_err(|e| {
+            error!("Unable to 'get-invites': {:?}", e);
+            e.into()
+        })
+}
+
+/// Change the registration mode or manage invites
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "type": "SET_REGISTRATION_MODE",
+///     "payload": {
+///         "mode": "CLOSED"
+///     }
+/// }
+/// ´´´
+///
+/// ´´´json
+/// {
+///     "type": "CREATE_INVITE",
+///     "payload": {
+///         "max_uses": 5,
+///         "expires": 1571486400
+///     }
+/// }
+/// ´´´
+///
+/// ´´´json
+/// {
+///     "type": "REVOKE_INVITE",
+///     "payload": {
+///         "code": "4f0d3c9a1b2e7d68"
+///     }
+/// }
+/// ´´´
+#[post("/admin/registration", format = "application/json", data = "<req>")]
+pub fn post_registration(
+    admin: Admin,
+    req: Option<Json<RegistrationRequest>>,
+) -> ApiResult<RegistrationSuccess> {
+    use self::RegistrationRequest::*;
+
+    let req = req.ok_or(ContentError::InvalidContent)?; // If invalid request give error.
+
+    match req.into_inner() {
+        SetRegistrationMode(p) => {
+            let mut mode = MODE
+                .write()
+                .map_err(|_| ResponseError::InternalServerError)?;
+            save_mode(p.mode).map_err(|e| {
+                error!("Unable to save registration mode: {}", e);
+                ResponseError::InternalServerError
+            })?;
+            *mode = p.mode;
+            info!("Admin ({:?}) set registration mode to {:?}", admin.id, p.mode);
+            Ok(Json(RegistrationSuccess::RegistrationMode(p.mode)))
+        }
+        CreateInvite(p) => {
+            let p = CreateInvitePayload {
+                code: crypto::random_hex(8),
+                created_by: admin.id,
+                expires: p.expires,
+                max_uses: p.max_uses.unwrap_or(1),
+            };
+
+            connect_to_auth()?
+                .create_invite(p)
+                .map(|v| {
+                    info!("Admin ({:?}) created an invite", admin.id);
+                    Json(RegistrationSuccess::InviteCreated(v))
+                }).map_err(|e| {
+                    error!("Unable to 'create-invite': {:?}", e);
+                    e.into()
+                })
+        }
+        RevokeInvite(p) => connect_to_auth()?
+            .revoke_invite(p.code)
+            .map(|_| {
+                info!("Admin ({:?}) revoked an invite", admin.id);
+                Json(RegistrationSuccess::InviteRevoked)
+            }).map_err(|e| {
+                error!("Unable to 'revoke-invite': {:?}", e);
+                e.into()
+            }),
+    }
+}
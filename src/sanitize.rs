@@ -0,0 +1,202 @@
+//! Sanitisation of user written text before it reaches the controller.
+//!
+//! Every text field of a thread or comment is normalised to Unicode NFC and
+//! stripped of control characters (except newlines and tabs) and of
+//! bidirectional overrides, which can make text display differently from what
+//! it contains. What happens to HTML in the text is configured per field:
+//!
+//! * `plain`: HTML is left as it is
+//! * `escape`: `<`, `>`, `&`, `"` and `'` are escaped, so HTML shows as text.
+//!   Character references like `&lt;` are kept, so text which is edited and
+//!   saved again is not escaped twice. Numeric references to characters which
+//!   would be stripped are escaped.
+//! * `html`: HTML is kept, but only a safe whitelist of tags and attributes,
+//!   see the defaults of `ammonia`
+//!
+//! The fields are configured by `SANITIZE_THREAD_TITLE` (default `escape`),
+//! `SANITIZE_THREAD_DESCRIPTION` and `SANITIZE_COMMENT_CONTENT` (default
+//! `html`, as these are markdown, see `markdown`).
+use std::convert::TryFrom;
+use unicode_normalization::UnicodeNormalization;
+
+use datatypes::content::requests::{
+    AddCommentPayload, AddThreadPayload, EditCommentPayload, EditThreadPayload,
+};
+use datatypes::content::responses::ContentError;
+
+lazy_static! {
+    static ref THREAD_TITLE: Mode = env_mode("SANITIZE_THREAD_TITLE", Mode::Escape);
+    static ref THREAD_DESCRIPTION: Mode = env_mode("SANITIZE_THREAD_DESCRIPTION", Mode::Html);
+    static ref COMMENT_CONTENT: Mode = env_mode("SANITIZE_COMMENT_CONTENT", Mode::Html);
+}
+
+/// What happens to HTML in a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Plain,
+    Escape,
+    Html,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Plain => "plain",
+            Mode::Escape => "escape",
+            Mode::Html => "html",
+        }
+    }
+}
+
+fn env_mode(name: &str, default: Mode) -> Mode {
+    match std::env::var(name).as_ref().map(String::as_str) {
+        Ok("plain") => Mode::Plain,
+        Ok("escape") => Mode::Escape,
+        Ok("html") => Mode::Html,
+        Ok(_) => {
+            warn!("{} is not one of plain, escape or html, using '{}'", name, default.name());
+            default
+        }
+        Err(_) => {
+            warn!("{} is not set, using '{}'", name, default.name());
+            default
+        }
+    }
+}
+
+/// Whether `c` is removed from all text
+fn is_stripped(c: char) -> bool {
+    match c {
+        '\n' | '\t' => false,
+        // Bidirectional embeddings, overrides and isolates
+        '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' => true,
+        _ => c.is_control(),
+    }
+}
+
+/// Whether `text` starts with a character reference, e.g. `&amp;`, `&#39;` or
+/// `&#x27;`
+///
+/// Numeric references only count if they refer to a character which would be
+/// kept in the text, so e.g. `&#x202E;` can't sneak in a bidirectional
+/// override.
+fn starts_with_reference(text: &str) -> bool {
+    let end = match text.find(';') {
+        Some(end) if text.starts_with('&') && end > 1 && end <= 32 => end,
+        _ => return false,
+    };
+    let name = &text[1..end];
+    let (digits, radix) = if name.starts_with("#x") || name.starts_with("#X") {
+        (&name[2..], 16)
+    } else if name.starts_with('#') {
+        (&name[1..], 10)
+    } else {
+        return name.chars().all(|c| c.is_ascii_alphanumeric());
+    };
+    // `from_str_radix` would also accept a sign
+    if !digits.chars().all(|c| c.is_digit(radix)) {
+        return false;
+    }
+    match u32::from_str_radix(digits, radix).ok().and_then(std::char::from_u32) {
+        Some(c) => !is_stripped(c),
+        None => false,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for (i, c) in text.char_indices() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' if starts_with_reference(&text[i..]) => escaped.push('&'),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Sanitise `text` according to `mode`
+pub fn sanitize_text(text: &str, mode: Mode) -> String {
+    let normalized: String = text
+        .replace("\r\n", "\n")
+        .nfc()
+        .filter(|c| !is_stripped(*c))
+        .collect();
+
+    match mode {
+        Mode::Plain => normalized,
+        Mode::Escape => escape_html(&normalized),
+        Mode::Html => ammonia::clean(&normalized),
+    }
+}
+
+/// Sanitise a validated field, which is validated again afterwards
+fn clean<T>(field: &str, value: &T, mode: Mode) -> Result<T, ContentError>
+where
+    T: AsRef<str> + TryFrom<String>,
+{
+    let text = sanitize_text(value.as_ref(), mode);
+    if text != value.as_ref() {
+        debug!("Sanitised {} ({})", field, mode.name());
+    }
+    T::try_from(text).map_err(|_| {
+        info!("Sanitised {} is no longer valid", field);
+        ContentError::InvalidContent
+    })
+}
+
+/// Sanitise a thread description as it would be when saved
+pub fn thread_description(text: &str) -> String {
+    sanitize_text(text, *THREAD_DESCRIPTION)
+}
+
+/// Sanitise comment content as it would be when saved
+pub fn comment_content(text: &str) -> String {
+    sanitize_text(text, *COMMENT_CONTENT)
+}
+
+pub fn add_thread(p: &mut AddThreadPayload) -> Result<(), ContentError> {
+    p.title = clean("thread title", &p.title, *THREAD_TITLE)?;
+    p.description = clean("thread description", &p.description, *THREAD_DESCRIPTION)?;
+    Ok(())
+}
+
+pub fn edit_thread(p: &mut EditThreadPayload) -> Result<(), ContentError> {
+    if let Some(title) = &p.title {
+        p.title = Some(clean("thread title", title, *THREAD_TITLE)?);
+    }
+    if let Some(description) = &p.description {
+        p.description = Some(clean("thread description", description, *THREAD_DESCRIPTION)?);
+    }
+    Ok(())
+}
+
+pub fn add_comment(p: &mut AddCommentPayload) -> Result<(), ContentError> {
+    p.content = clean("comment content", &p.content, *COMMENT_CONTENT)?;
+    Ok(())
+}
+
+pub fn edit_comment(p: &mut EditCommentPayload) -> Result<(), ContentError> {
+    p.content = clean("comment content", &p.content, *COMMENT_CONTENT)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_keeps_only_references_to_kept_characters() {
+        assert_eq!(escape_html("&lt; &amp; &#39; &#x27;"), "&lt; &amp; &#39; &#x27;");
+        assert_eq!(escape_html("&#x202E;"), "&amp;#x202E;");
+        assert_eq!(escape_html("&#8238;"), "&amp;#8238;");
+        assert_eq!(escape_html("&#0;"), "&amp;#0;");
+        assert_eq!(escape_html("&#xD800;"), "&amp;#xD800;");
+        assert_eq!(escape_html("&#99999999999;"), "&amp;#99999999999;");
+        assert_eq!(escape_html("&#x+41;"), "&amp;#x+41;");
+    }
+}
@@ -0,0 +1,363 @@
+//! Binding sessions to the context they were created in.
+//!
+//! At login the gate stores a second private cookie next to the session
+//! cookie. It holds digests of the session token, the role of the user, the
+//! network prefix of the client address (/24 for IPv4, /48 for IPv6) and the
+//! user agent. Every time the session is presented the current request is
+//! compared against it. `SESSION_BINDING` decides what happens on a mismatch:
+//!
+//! * `off`: nothing is compared (default)
+//! * `log`: the mismatch is logged
+//! * `user-agent`: the mismatch is logged, and the request is rejected if the
+//!   user agent changed
+//! * `strict`: the mismatch is logged, and the request is rejected if the user
+//!   agent or the network changed
+//!
+//! The cookie is only a copy of the context the auth service recorded when the
+//! session was created. If a session is presented without it, the recorded
+//! context is used instead and the cookie is restored from it, so dropping the
+//! cookie does not bind the session to whoever presents it. With binding
+//! `off` the recorded context is not looked up.
+//!
+//! Independent of the mode the session token is rotated when the role of the
+//! user changed since the session was bound, as far as the binding is known.
+//! The sudo confirmation of the session moves to the new token.
+//!
+//! Request guards cannot set cookies reliably, so restored bindings and rotated
+//! tokens are queued and handed to the client by the `SessionCookies` fairing.
+use chrono::prelude::*;
+use chrono::Duration;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Cookie, Cookies};
+use rocket::{Request, Response};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use datatypes::auth::responses::Role;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::{Token, USER_TOKEN_NAME};
+
+use crate::auth::connect_to_auth;
+use crate::crypto;
+use crate::sudo;
+use crate::token_cache::{RecordedContext, TOKEN_CACHE};
+
+/// Name of the cookie holding the binding
+const BINDING_COOKIE_NAME: &str = "session_binding";
+
+lazy_static! {
+    static ref MODE: BindingMode = match std::env::var("SESSION_BINDING") {
+        Ok(ref value) if value == "off" => BindingMode::Off,
+        Ok(ref value) if value == "log" => BindingMode::Log,
+        Ok(ref value) if value == "user-agent" => BindingMode::UserAgent,
+        Ok(ref value) if value == "strict" => BindingMode::Strict,
+        Ok(value) => {
+            warn!("Unknown SESSION_BINDING '{}', using 'off'", value);
+            BindingMode::Off
+        }
+        Err(_) => {
+            warn!("SESSION_BINDING is not set, using 'off'");
+            BindingMode::Off
+        }
+    };
+    /// Cookies to set on the response to the request presenting a token
+    static ref QUEUED: Mutex<HashMap<Token, Queued>> = Mutex::new(HashMap::new());
+}
+
+struct Queued {
+    cookies: Vec<Cookie<'static>>,
+    expires: DateTime<Utc>,
+}
+
+/// Set `cookies` on the response to the request which presented `token`
+fn queue(token: &Token, cookies: Vec<Cookie<'static>>) {
+    match QUEUED.lock() {
+        Ok(mut queued) => {
+            let now = Utc::now();
+            queued.retain(|_, q| q.expires > now);
+            queued.insert(
+                token.clone(),
+                Queued {
+                    cookies,
+                    expires: now + Duration::minutes(1),
+                },
+            );
+        }
+        Err(e) => error!("Error writing to queued session cookies: {}", e),
+    }
+}
+
+/// Hands restored bindings and rotated session tokens to the client
+pub struct SessionCookies;
+
+impl Fairing for SessionCookies {
+    fn info(&self) -> Info {
+        Info {
+            name: "set queued session cookies",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        let token: Token = match req.cookies().get_private(USER_TOKEN_NAME) {
+            Some(cookie) => cookie.into(),
+            None => return,
+        };
+        let queued = match QUEUED.lock() {
+            Ok(mut queued) => queued.remove(&token),
+            Err(e) => {
+                error!("Error reading queued session cookies: {}", e);
+                return;
+            }
+        };
+
+        if let Some(queued) = queued {
+            let mut cookies = req.cookies();
+            let names: Vec<String> = queued
+                .cookies
+                .iter()
+                .map(|cookie| cookie.name().to_string())
+                .collect();
+            for cookie in queued.cookies {
+                cookies.add_private(cookie);
+            }
+            // The cookies set by the route were already added to the response
+            for cookie in cookies.delta() {
+                if names.iter().any(|name| name == cookie.name()) {
+                    res.adjoin_header(cookie);
+                }
+            }
+        }
+    }
+}
+
+/// How strictly sessions are bound to their context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BindingMode {
+    Off,
+    Log,
+    UserAgent,
+    Strict,
+}
+
+/// The context a session is bound to, as digests
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fingerprint {
+    token: String,
+    role: String,
+    network: String,
+    user_agent: String,
+}
+
+impl Fingerprint {
+    fn new(
+        token: &Token,
+        role: Role,
+        ip: Option<IpAddr>,
+        user_agent: Option<&str>,
+    ) -> Fingerprint {
+        let token: Cookie = token.clone().into();
+        Fingerprint {
+            token: crypto::sha256_hex(token.value().as_bytes()),
+            role: format!("{:?}", role),
+            network: crypto::sha256_hex(network(ip).as_bytes()),
+            user_agent: crypto::sha256_hex(user_agent.unwrap_or("").as_bytes()),
+        }
+    }
+
+    fn from_request(req: &Request, token: &Token, role: Role) -> Fingerprint {
+        Fingerprint::new(
+            token,
+            role,
+            req.remote().map(|addr| addr.ip()),
+            req.headers().get_one("User-Agent"),
+        )
+    }
+
+    fn parse(value: &str) -> Option<Fingerprint> {
+        let mut parts = value.split(':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(token), Some(role), Some(network), Some(user_agent)) => Some(Fingerprint {
+                token: token.to_string(),
+                role: role.to_string(),
+                network: network.to_string(),
+                user_agent: user_agent.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn into_cookie(self) -> Cookie<'static> {
+        let value = format!(
+            "{}:{}:{}:{}",
+            self.token, self.role, self.network, self.user_agent
+        );
+        let mut cookie = Cookie::new(BINDING_COOKIE_NAME, value);
+        cookie.set_path("/");
+        cookie.set_http_only(true);
+        cookie
+    }
+}
+
+/// The network prefix of an address
+fn network(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(IpAddr::V4(ip)) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+        }
+        Some(IpAddr::V6(ip)) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+        None => String::new(),
+    }
+}
+
+/// Bind a new session to the request which created it
+pub fn bind(
+    cookies: &mut Cookies,
+    token: &Token,
+    role: Role,
+    ip: Option<IpAddr>,
+    user_agent: Option<&str>,
+) {
+    cookies.add_private(Fingerprint::new(token, role, ip, user_agent).into_cookie());
+}
+
+/// Forget the binding when the session ends
+pub fn unbind(cookies: &mut Cookies) {
+    cookies.remove_private(Cookie::named(BINDING_COOKIE_NAME));
+}
+
+/// The binding of a session from the context the auth service recorded
+///
+/// The role is not recorded, so the current one is used. The answer is kept in the token cache, so sessions without a binding
+/// cookie do not cost a round-trip on every request.
+fn recorded(token: &Token, id: UserId, role: Role) -> Result<Option<Fingerprint>, ResponseError> {
+    let context = match TOKEN_CACHE.get_context(token) {
+        Some(context) => context,
+        None => {
+            let context = connect_to_auth()?
+                .get_sessions(token.clone())?
+                .into_iter()
+                .find(|session| session.current)
+                .map(|session| RecordedContext {
+                    ip: session.ip,
+                    user_agent: session.user_agent,
+                });
+            if context.is_none() {
+                // Sessions created before binding was introduced have no
+                // recorded context
+                warn!(
+                    "Session event: session of user ({:?}) presented without a binding",
+                    id
+                );
+            }
+            TOKEN_CACHE.insert_context(token.clone(), id, context.clone());
+            context
+        }
+    };
+    Ok(context.map(|context| {
+        Fingerprint::new(
+            token,
+            role,
+            context.ip,
+            context.user_agent.as_ref().map(String::as_str),
+        )
+    }))
+}
+
+/// Check a session against its binding
+///
+/// Returns the token which should be used for the rest of the request, which
+/// differs from `token` if the session was rotated.
+pub fn check(
+    req: &Request,
+    token: Token,
+    id: UserId,
+    role: Role,
+) -> Result<Token, ResponseError> {
+    let current = Fingerprint::from_request(req, &token, role);
+    let cookie = req
+        .cookies()
+        .get_private(BINDING_COOKIE_NAME)
+        .and_then(|cookie| Fingerprint::parse(cookie.value()))
+        .filter(|bound| bound.token == current.token);
+
+    let bound = match cookie {
+        Some(bound) => bound,
+        // Without binding only the role is compared, which needs the cookie
+        None if *MODE == BindingMode::Off => return Ok(token),
+        None => match recorded(&token, id, role)? {
+            Some(bound) => {
+                debug!(
+                    "Session event: restoring binding of user ({:?}) from the auth service",
+                    id
+                );
+                queue(&token, vec![bound.clone().into_cookie()]);
+                bound
+            }
+            None => {
+                if *MODE == BindingMode::Strict {
+                    return Err(ResponseError::Unauthenticated);
+                }
+                return Ok(token);
+            }
+        },
+    };
+
+    if *MODE != BindingMode::Off {
+        let network_changed = bound.network != current.network;
+        let user_agent_changed = bound.user_agent != current.user_agent;
+
+        if network_changed || user_agent_changed {
+            warn!(
+                "Session event: session of user ({:?}) presented from an unexpected context \
+                 (network changed: {}, user agent changed: {})",
+                id, network_changed, user_agent_changed
+            );
+            let reject = match *MODE {
+                BindingMode::Strict => network_changed || user_agent_changed,
+                BindingMode::UserAgent => user_agent_changed,
+                _ => false,
+            };
+            if reject {
+                return Err(ResponseError::Unauthenticated);
+            }
+        }
+    }
+
+    if bound.role != current.role {
+        return rotate(token, id, role, bound);
+    }
+    Ok(token)
+}
+
+/// Replace the session token, keeping the context the session was bound to
+fn rotate(
+    token: Token,
+    id: UserId,
+    role: Role,
+    bound: Fingerprint,
+) -> Result<Token, ResponseError> {
+    let new_token = connect_to_auth()?.rotate_session(token.clone())?;
+    TOKEN_CACHE.invalidate_token(&token);
+    sudo::carry_over(&token, &new_token);
+
+    let token_cookie: Cookie<'static> = new_token.clone().into();
+    let binding = Fingerprint {
+        token: crypto::sha256_hex(token_cookie.value().as_bytes()),
+        role: format!("{:?}", role),
+        ..bound
+    };
+    queue(&token, vec![token_cookie, binding.into_cookie()]);
+
+    info!(
+        "Session event: rotated session of user ({:?}) after a role change",
+        id
+    );
+    Ok(new_token)
+}
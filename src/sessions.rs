@@ -0,0 +1,155 @@
+//! API-routes to list and revoke the sessions of a user.
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+use rocket_contrib::Json;
+
+use datatypes::content::responses::ContentError;
+
+use crate::auth::connect_to_auth;
+use crate::comms::auth::{RevokeSessionPayload, SessionId, SessionPayload};
+use crate::guards::AuthenticatedUser;
+use crate::token_cache::TOKEN_CACHE;
+use crate::JsonResponseResult;
+
+/// The `User-Agent` header of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for UserAgent {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<UserAgent, ()> {
+        match req.headers().get_one("User-Agent") {
+            Some(value) => Outcome::Success(UserAgent(value.to_string())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Requests which revoke one or more sessions of the logged in user
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SessionRequest {
+    RevokeSession(RevokeSessionForm),
+    RevokeOtherSessions,
+}
+
+/// The id of the session to revoke
+#[derive(Deserialize, Debug)]
+pub struct RevokeSessionForm {
+    pub id: SessionId,
+}
+
+/// The outcome of a successful session request
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SessionSuccess {
+    Sessions(Vec<SessionPayload>),
+    SessionRevoked,
+    OtherSessionsRevoked,
+}
+
+/// Get the active sessions of the logged in user
+///
+/// # Example
+///
+/// ## Query
+///
+/// ´´´text
+/// localhost:9234/api/auth/sessions
+/// ´´´
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "type": "SESSIONS",
+///     "payload": [{
+///         "id": 3,
+///         "created": 1539950400,
+///         "last_seen": 1539954000,
+///         "ip": "195.168.1.2",
+///         "user_agent": "Mozilla/5.0 (X11; Linux x86_64)",
+///         "current": true
+///     }]
+/// }
+/// ´´´
+#[get("/auth/sessions")]
+pub fn get_sessions(user: AuthenticatedUser) -> JsonResponseResult<SessionSuccess> {
+    info!("Requesting sessions of user");
+    let token = user.session_token().map_err(Json)?.clone();
+
+    connect_to_auth()
+        .map_err(Json)?
+        .get_sessions(token)
+        .map(|v| {
+            info!("Returning success from 'get-sessions' request");
+            Json(SessionSuccess::Sessions(v))
+        }).map_err(|e| {
+            error!("Unable to 'get-sessions': {:?}", e);
+            Json(e.into())
+        })
+}
+
+/// Revoke a single session or all sessions except the current one
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "type": "REVOKE_SESSION",
+///     "payload": {
+///         "id": 3
+///     }
+/// }
+/// ´´´
+///
+/// ´´´json
+/// {
+///     "type": "REVOKE_OTHER_SESSIONS"
+/// }
+/// ´´´
+#[post("/auth/sessions", format = "application/json", data = "<req>")]
+pub fn post_sessions(
+    user: AuthenticatedUser,
+    req: Option<Json<SessionRequest>>,
+) -> JsonResponseResult<SessionSuccess> {
+    use self::SessionRequest::*;
+
+    let req = req
+        .ok_or(ContentError::InvalidContent)
+        .map_err(|e| Json(e.into()))?; // If invalid request give error.
+
+    let token = user.session_token().map_err(Json)?.clone();
+
+    // The revoked tokens are unknown to the gate, so every cached token of
+    // the user has to go. This happens after revoking, otherwise a concurrent
+    // request could cache a revoked token again.
+    match req.into_inner() {
+        RevokeSession(p) => {
+            let p = RevokeSessionPayload { token, id: p.id };
+            connect_to_auth()
+                .map_err(Json)?
+                .revoke_session(p)
+                .map(|_| {
+                    TOKEN_CACHE.invalidate_user(user.id);
+                    info!("Returning success from 'revoke-session' request");
+                    Json(SessionSuccess::SessionRevoked)
+                }).map_err(|e| {
+                    error!("Unable to 'revoke-session': {:?}", e);
+                    Json(e.into())
+                })
+        }
+        RevokeOtherSessions => connect_to_auth()
+            .map_err(Json)?
+            .revoke_other_sessions(token)
+            .map(|_| {
+                TOKEN_CACHE.invalidate_user(user.id);
+                info!("Returning success from 'revoke-other-sessions' request");
+                Json(SessionSuccess::OtherSessionsRevoked)
+            }).map_err(|e| {
+                error!("Unable to 'revoke-other-sessions': {:?}", e);
+                Json(e.into())
+            }),
+    }
+}
@@ -0,0 +1,181 @@
+//! Re-authentication ("sudo mode") for dangerous actions.
+//!
+//! A valid session alone is not enough for some admin actions. The user has
+//! to confirm the password, or a TOTP code if two-factor authentication is
+//! enabled, through `/api/auth/reauthenticate` first. The confirmation is
+//! remembered for the session for `SUDO_WINDOW` seconds (default 300).
+use chrono::prelude::*;
+use chrono::Duration;
+use rocket_contrib::Json;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use datatypes::auth::requests::AuthPayload;
+use datatypes::auth::responses::AuthError;
+use datatypes::content::requests::GetUserPayload;
+use datatypes::error::ResponseError;
+use datatypes::valid::fields::PlainPassword;
+use datatypes::valid::token::Token;
+
+use crate::auth::connect_to_auth;
+use crate::content::connect_to_controller;
+use crate::error::{ApiResult, GateError};
+use crate::guards::AuthenticatedUser;
+use crate::totp;
+
+lazy_static! {
+    static ref WINDOW: Duration = match std::env::var("SUDO_WINDOW") {
+        Ok(value) => Duration::seconds(value.parse::<i64>().unwrap_or_else(|_| {
+            warn!("SUDO_WINDOW is not a number, using '300'");
+            300
+        })),
+        Err(_) => {
+            warn!("SUDO_WINDOW is not set, using '300'");
+            Duration::seconds(300)
+        }
+    };
+    /// When a session last confirmed the credentials, by session token
+    static ref CONFIRMED: RwLock<HashMap<Token, DateTime<Utc>>> = RwLock::new(HashMap::new());
+}
+
+/// Fail with `ReauthenticationRequired` unless the session of `user` confirmed
+/// the credentials recently
+///
+/// API keys can never be used for sensitive actions.
+pub fn require(user: &AuthenticatedUser) -> Result<(), GateError> {
+    let token = user
+        .session_token()
+        .map_err(|_| GateError::ReauthenticationRequired)?;
+
+    let confirmed = CONFIRMED
+        .read()
+        .map_err(|e| {
+            error!("Error reading sudo confirmations: {}", e);
+            GateError::ReauthenticationRequired
+        })?.get(token)
+        .map_or(false, |at| *at + *WINDOW > Utc::now());
+
+    if confirmed {
+        Ok(())
+    } else {
+        info!("User ({:?}) needs to reauthenticate", user.id);
+        Err(GateError::ReauthenticationRequired)
+    }
+}
+
+/// Keep the confirmation of a session whose token was rotated
+pub fn carry_over(old: &Token, new: &Token) {
+    match CONFIRMED.write() {
+        Ok(mut confirmed) => {
+            if let Some(at) = confirmed.remove(old) {
+                confirmed.insert(new.clone(), at);
+            }
+        }
+        Err(e) => error!("Error writing to sudo confirmations: {}", e),
+    }
+}
+
+/// Credentials confirming that the user is still at the keyboard
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReauthenticateRequest {
+    Password(ReauthenticatePasswordForm),
+    Totp(ReauthenticateTotpForm),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReauthenticatePasswordForm {
+    pub password: PlainPassword,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReauthenticateTotpForm {
+    pub code: String,
+}
+
+/// The outcome of a successful reauthentication
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReauthenticateSuccess {
+    /// Seconds since the unix epoch until which sensitive actions are allowed
+    Reauthenticated(i64),
+}
+
+/// Confirm the password or a TOTP code to allow sensitive actions
+///
+/// # Example
+///
+/// ´´´json
+/// {
+///     "type": "PASSWORD",
+///     "payload": {
+///         "password": "secret"
+///     }
+/// }
+/// ´´´
+///
+/// ´´´json
+/// {
+///     "type": "TOTP",
+///     "payload": {
+///         "code": "123456"
+///     }
+/// }
+/// ´´´
+///
+/// ## Result
+///
+/// ´´´json
+/// {
+///     "type": "REAUTHENTICATED",
+///     "payload": 1539950700
+/// }
+/// ´´´
+#[post("/auth/reauthenticate", format = "application/json", data = "<req>")]
+pub fn reauthenticate(
+    user: AuthenticatedUser,
+    req: Option<Json<ReauthenticateRequest>>,
+) -> ApiResult<ReauthenticateSuccess> {
+    use self::ReauthenticateRequest::*;
+
+    let req = req.ok_or(AuthError::InvalidCredentials)?; // If invalid request query.
+    let token = user.session_token()?.clone();
+    let auth = connect_to_auth()?;
+
+    match req.into_inner() {
+        Password(p) => {
+            // The username is only known to the controller
+            let username = connect_to_controller()?
+                .get_user(GetUserPayload { id: user.id })?
+                .username;
+
+            let credentials = AuthPayload {
+                username,
+                password: p.password,
+            };
+            let confirmation = auth.authenticate(credentials).map_err(|e| {
+                info!("User ({:?}) failed to reauthenticate: {:?}", user.id, e);
+                e
+            })?;
+            // Only the check is needed, not the new session
+            if let Err(e) = auth.deauthenticate(confirmation) {
+                warn!("Unable to 'deauthenticate' confirmation session: {:?}", e);
+            }
+        }
+        Totp(p) => totp::verify(&auth, user.id, &p.code)?,
+    }
+
+    let now = Utc::now();
+    {
+        let mut confirmed = CONFIRMED
+            .write()
+            .map_err(|_| ResponseError::InternalServerError)?;
+        confirmed.retain(|_, at| *at + *WINDOW > now);
+        confirmed.insert(token, now);
+    }
+
+    info!("User ({:?}) reauthenticated", user.id);
+    Ok(Json(ReauthenticateSuccess::Reauthenticated(
+        (now + *WINDOW).timestamp(),
+    )))
+}
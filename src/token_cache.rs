@@ -0,0 +1,247 @@
+//! In-process cache of `Token -> (UserId, Role)` lookups.
+//!
+//! Almost every request needs to know who is behind a token, and asking the
+//! auth service costs a fresh connection and a round-trip. The answers are
+//! therefore kept for a short while (`TOKEN_CACHE_TTL` seconds, `0` disables
+//! the cache). Entries must be invalidated whenever the answer may change,
+//! e.g. when a session ends or a user gets a new role.
+//!
+//! Next to them the context the auth service recorded for a session is kept,
+//! which `session_binding` needs when a session is presented without its
+//! binding cookie, and the categories a user was granted moderation of,
+//! which reads of hidden content need.
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use datatypes::auth::responses::Role;
+use datatypes::valid::ids::{CategoryId, UserId};
+use datatypes::valid::token::Token;
+
+lazy_static! {
+    pub static ref TOKEN_CACHE: TokenCache = {
+        let ttl = match std::env::var("TOKEN_CACHE_TTL") {
+            Ok(value) => value.parse::<i64>().unwrap_or_else(|_| {
+                warn!("TOKEN_CACHE_TTL is not a number, using '5'");
+                5
+            }),
+            Err(_) => {
+                warn!("TOKEN_CACHE_TTL is not set, using '5'");
+                5
+            }
+        };
+        TokenCache::new(Duration::seconds(ttl))
+    };
+}
+
+struct Entry {
+    id: UserId,
+    role: Role,
+    expires: DateTime<Utc>,
+}
+
+/// The context the auth service recorded when a session was created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedContext {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+struct ContextEntry {
+    id: UserId,
+    /// `None` for sessions created before contexts were recorded
+    context: Option<RecordedContext>,
+    expires: DateTime<Utc>,
+}
+
+struct GrantsEntry {
+    grants: Vec<CategoryId>,
+    expires: DateTime<Utc>,
+}
+
+pub struct TokenCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<Token, Entry>>,
+    contexts: RwLock<HashMap<Token, ContextEntry>>,
+    grants: RwLock<HashMap<UserId, GrantsEntry>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl TokenCache {
+    pub fn new(ttl: Duration) -> TokenCache {
+        TokenCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+            contexts: RwLock::new(HashMap::new()),
+            grants: RwLock::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Look up a token, counting the lookup as a hit or a miss
+    pub fn get(&self, token: &Token) -> Option<(UserId, Role)> {
+        let found = match self.entries.read() {
+            Ok(entries) => entries
+                .get(token)
+                .filter(|entry| entry.expires > Utc::now())
+                .map(|entry| (entry.id, entry.role)),
+            Err(e) => {
+                error!("Error reading token cache: {}", e);
+                None
+            }
+        };
+
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        trace!("Token cache: {} hits, {} misses", self.hits(), self.misses());
+        found
+    }
+
+    pub fn insert(&self, token: Token, (id, role): (UserId, Role)) {
+        if self.ttl <= Duration::zero() {
+            return;
+        }
+
+        let now = Utc::now();
+        match self.entries.write() {
+            Ok(mut entries) => {
+                entries.retain(|_, entry| entry.expires > now);
+                entries.insert(
+                    token,
+                    Entry {
+                        id,
+                        role,
+                        expires: now + self.ttl,
+                    },
+                );
+            }
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+    }
+
+    /// Look up the recorded context of a session
+    ///
+    /// Returns `Some(None)` if the session is known to have no recorded
+    /// context.
+    pub fn get_context(&self, token: &Token) -> Option<Option<RecordedContext>> {
+        match self.contexts.read() {
+            Ok(contexts) => contexts
+                .get(token)
+                .filter(|entry| entry.expires > Utc::now())
+                .map(|entry| entry.context.clone()),
+            Err(e) => {
+                error!("Error reading token cache: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn insert_context(&self, token: Token, id: UserId, context: Option<RecordedContext>) {
+        if self.ttl <= Duration::zero() {
+            return;
+        }
+
+        let now = Utc::now();
+        match self.contexts.write() {
+            Ok(mut contexts) => {
+                contexts.retain(|_, entry| entry.expires > now);
+                contexts.insert(
+                    token,
+                    ContextEntry {
+                        id,
+                        context,
+                        expires: now + self.ttl,
+                    },
+                );
+            }
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+    }
+
+    /// Look up the categories a user was granted moderation of
+    pub fn get_grants(&self, id: UserId) -> Option<Vec<CategoryId>> {
+        match self.grants.read() {
+            Ok(grants) => grants
+                .get(&id)
+                .filter(|entry| entry.expires > Utc::now())
+                .map(|entry| entry.grants.clone()),
+            Err(e) => {
+                error!("Error reading token cache: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn insert_grants(&self, id: UserId, grants: Vec<CategoryId>) {
+        if self.ttl <= Duration::zero() {
+            return;
+        }
+
+        let now = Utc::now();
+        match self.grants.write() {
+            Ok(mut entries) => {
+                entries.retain(|_, entry| entry.expires > now);
+                entries.insert(
+                    id,
+                    GrantsEntry {
+                        grants,
+                        expires: now + self.ttl,
+                    },
+                );
+            }
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+    }
+
+    /// Forget a single token, e.g. when it is deauthenticated
+    pub fn invalidate_token(&self, token: &Token) {
+        match self.entries.write() {
+            Ok(mut entries) => {
+                entries.remove(token);
+            }
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+        match self.contexts.write() {
+            Ok(mut contexts) => {
+                contexts.remove(token);
+            }
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+    }
+
+    /// Forget all tokens of a user, e.g. when the role of the user changes
+    ///
+    /// This also forgets the moderator grants of the user.
+    pub fn invalidate_user(&self, id: UserId) {
+        match self.entries.write() {
+            Ok(mut entries) => entries.retain(|_, entry| entry.id != id),
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+        match self.contexts.write() {
+            Ok(mut contexts) => contexts.retain(|_, entry| entry.id != id),
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+        match self.grants.write() {
+            Ok(mut grants) => {
+                grants.remove(&id);
+            }
+            Err(e) => error!("Error writing to token cache: {}", e),
+        }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,662 @@
+//! Optional TOTP two-factor authentication (RFC 6238).
+//!
+//! The secrets are stored by the auth service, but codes are generated and
+//! verified by the gate. When a user with two-factor authentication enabled
+//! logs in, the session token is held back by the gate and the client is
+//! handed a challenge instead. The session is only handed out once the
+//! challenge is answered with a valid code through `/api/auth/totp`.
+//!
+//! Users with at least the role in `TOTP_REQUIRED_ROLE` (`none`, `moderator`
+//! or `admin`) must enroll before their login can finish.
+//!
+//! Wrong codes are counted per user, across all of their challenges. After
+//! `MAX_FAILURES` wrong codes the user is locked out of TOTP for
+//! `LOCKOUT_MINUTES`, so codes cannot be guessed by logging in again and again.
+use chrono::prelude::*;
+use chrono::Duration;
+use hmac::{Hmac, Mac};
+use rocket::http::Cookies;
+use rocket_contrib::Json;
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use datatypes::auth::responses::{AuthError, Role};
+use datatypes::content::requests::GetUserPayload;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::UserId;
+
+use crate::auth::{self, connect_to_auth};
+use crate::comms::auth::SyncClient as AuthClient;
+use crate::comms::auth::{SessionContextPayload, SetTotpPayload, TotpPayload};
+use crate::content::connect_to_controller;
+use crate::crypto;
+use crate::error::{ApiError, ApiResult, ChallengePayload, GateError};
+use crate::guards::AuthenticatedUser;
+
+/// Length of a time step in seconds
+const STEP: i64 = 30;
+/// Number of digits in a code
+const DIGITS: u32 = 6;
+/// Number of steps before and after the current one which are accepted
+const SKEW: i64 = 1;
+/// Number of recovery codes handed out on enrollment
+const RECOVERY_CODES: usize = 8;
+/// Number of wrong codes accepted for a single login challenge
+const MAX_ATTEMPTS: u32 = 5;
+/// Number of wrong codes of a user before they are locked out
+const MAX_FAILURES: u32 = 10;
+/// Minutes a user is locked out for after too many wrong codes
+const LOCKOUT_MINUTES: i64 = 15;
+
+lazy_static! {
+    /// Users with at least this role must use two-factor authentication
+    static ref REQUIRED_ROLE: Option<Role> = match std::env::var("TOTP_REQUIRED_ROLE") {
+        Ok(ref value) if value == "none" => None,
+        Ok(ref value) if value == "moderator" => Some(Role::Moderator),
+        Ok(ref value) if value == "admin" => Some(Role::Admin),
+        Ok(value) => {
+            warn!("Unknown TOTP_REQUIRED_ROLE '{}', using 'none'", value);
+            None
+        }
+        Err(_) => {
+            warn!("TOTP_REQUIRED_ROLE is not set, using 'none'");
+            None
+        }
+    };
+    static ref ISSUER: String = match std::env::var("TOTP_ISSUER") {
+        Ok(value) => value,
+        Err(_) => {
+            warn!("TOTP_ISSUER is not set, using 'security-gate'");
+            "security-gate".to_string()
+        }
+    };
+    static ref PENDING_LOGINS: RwLock<HashMap<String, PendingLogin>> = RwLock::new(HashMap::new());
+    static ref PENDING_ENROLLMENTS: RwLock<HashMap<UserId, PendingEnrollment>> =
+        RwLock::new(HashMap::new());
+    /// The last time step used by each user, to prevent replaying a code
+    static ref LAST_STEPS: RwLock<HashMap<UserId, i64>> = RwLock::new(HashMap::new());
+    /// Wrong codes of each user since their last correct one
+    static ref FAILURES: RwLock<HashMap<UserId, Failures>> = RwLock::new(HashMap::new());
+}
+
+/// Wrong codes of a user
+struct Failures {
+    count: u32,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// A login which is waiting for the second factor
+struct PendingLogin {
+    id: UserId,
+    context: SessionContextPayload,
+    attempts: u32,
+    expires: DateTime<Utc>,
+}
+
+/// An enrollment which is waiting for the first code from the new secret
+struct PendingEnrollment {
+    totp: TotpPayload,
+    expires: DateTime<Utc>,
+}
+
+/// Whether `role` must use two-factor authentication
+pub fn is_required_for(role: Role) -> bool {
+    REQUIRED_ROLE.map_or(false, |required| role >= required)
+}
+
+/// Compute the HOTP value of `key` at `counter` (RFC 4226)
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any length");
+    let mut message = [0u8; 8];
+    for (i, byte) in message.iter_mut().enumerate() {
+        *byte = (counter >> (56 - 8 * i)) as u8;
+    }
+    mac.input(&message);
+    let hash = mac.result().code();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// Check `code` against the base32 encoded `secret` at the current time
+///
+/// Each time step can only be used once per user.
+fn verify_code(id: UserId, secret: &str, code: &str) -> bool {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let code: u32 = match code.parse() {
+        Ok(code) => code,
+        Err(_) => return false,
+    };
+    let key = match base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret) {
+        Some(key) => key,
+        None => {
+            error!("Invalid TOTP secret stored for user {:?}", id);
+            return false;
+        }
+    };
+
+    let current = Utc::now().timestamp() / STEP;
+    let step = match (current - SKEW..=current + SKEW).find(|&step| hotp(&key, step as u64) == code)
+    {
+        Some(step) => step,
+        None => return false,
+    };
+
+    let mut last_steps = match LAST_STEPS.write() {
+        Ok(last_steps) => last_steps,
+        Err(e) => {
+            error!("Error writing to TOTP steps: {}", e);
+            return false;
+        }
+    };
+    if last_steps.get(&id).map_or(false, |&last| step <= last) {
+        warn!("User {:?} tried to reuse a TOTP code", id);
+        return false;
+    }
+    last_steps.insert(id, step);
+    true
+}
+
+/// Fail if the user is locked out after too many wrong codes
+fn check_lockout(id: UserId) -> Result<(), ApiError> {
+    let failures = FAILURES
+        .read()
+        .map_err(|_| ResponseError::InternalServerError)?;
+    match failures.get(&id).and_then(|f| f.locked_until) {
+        Some(until) if until > Utc::now() => {
+            info!("User {:?} is locked out of TOTP until {}", id, until);
+            Err(GateError::TotpLockedOut.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Count a wrong code of the user, locking them out after too many
+fn record_failure(id: UserId) {
+    match FAILURES.write() {
+        Ok(mut failures) => {
+            let now = Utc::now();
+            let entry = failures.entry(id).or_insert(Failures {
+                count: 0,
+                locked_until: None,
+            });
+            // A lockout which ran out starts a new round
+            if entry.locked_until.map_or(false, |until| until <= now) {
+                entry.count = 0;
+                entry.locked_until = None;
+            }
+            entry.count += 1;
+            if entry.count >= MAX_FAILURES {
+                warn!("Too many wrong TOTP codes, locking out user {:?}", id);
+                entry.locked_until = Some(now + Duration::minutes(LOCKOUT_MINUTES));
+            }
+        }
+        Err(e) => error!("Error writing to TOTP failures: {}", e),
+    }
+}
+
+/// Forget the wrong codes of the user after a correct one
+fn clear_failures(id: UserId) {
+    match FAILURES.write() {
+        Ok(mut failures) => {
+            failures.remove(&id);
+        }
+        Err(e) => error!("Error writing to TOTP failures: {}", e),
+    }
+}
+
+/// Check `code` against the secret, counting wrong codes towards a lockout
+fn verify_counted(id: UserId, secret: &str, code: &str) -> Result<(), ApiError> {
+    check_lockout(id)?;
+    if verify_code(id, secret, code) {
+        clear_failures(id);
+        Ok(())
+    } else {
+        record_failure(id);
+        Err(GateError::InvalidTotpCode.into())
+    }
+}
+
+/// Normalize and hash a recovery code for storage
+fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    crypto::sha256_hex(code.as_bytes())
+}
+
+/// Check `code` as a TOTP code or a recovery code of the user
+///
+/// A used recovery code is removed from the stored state. Wrong codes count
+/// towards a lockout of the user.
+pub fn verify(auth: &AuthClient, id: UserId, code: &str) -> Result<(), ApiError> {
+    check_lockout(id)?;
+    let totp = match auth.get_totp(id)? {
+        Some(totp) => totp,
+        None => return Err(GateError::InvalidTotpCode.into()),
+    };
+
+    if verify_code(id, &totp.secret, code) {
+        clear_failures(id);
+        return Ok(());
+    }
+
+    let hash = hash_recovery_code(code);
+    if !totp.recovery_codes.contains(&hash) {
+        record_failure(id);
+        return Err(GateError::InvalidTotpCode.into());
+    }
+
+    clear_failures(id);
+    info!("User {:?} used a recovery code", id);
+    let recovery_codes = totp
+        .recovery_codes
+        .into_iter()
+        .filter(|c| c != &hash)
+        .collect();
+    auth.set_totp(SetTotpPayload {
+        id,
+        totp: Some(TotpPayload {
+            secret: totp.secret,
+            recovery_codes,
+        }),
+    })?;
+    Ok(())
+}
+
+/// Decide whether a login needs a second factor before it can finish
+///
+/// Returns `Ok(())` if the session can be handed out right away, and a
+/// `TotpRequired` or `TotpEnrollmentRequired` challenge otherwise.
+pub fn check_login(
+    auth: &AuthClient,
+    id: UserId,
+    role: Role,
+    context: SessionContextPayload,
+) -> Result<(), ApiError> {
+    let enrolled = auth.get_totp(id)?.is_some();
+    if !enrolled && !is_required_for(role) {
+        return Ok(());
+    }
+
+    let challenge = crypto::random_hex(16);
+    let pending = PendingLogin {
+        id,
+        context,
+        attempts: 0,
+        expires: Utc::now() + Duration::minutes(5),
+    };
+    let expired: Vec<PendingLogin> = {
+        let mut pending_logins = PENDING_LOGINS
+            .write()
+            .map_err(|_| ResponseError::InternalServerError)?;
+        let now = Utc::now();
+        let expired: Vec<String> = pending_logins
+            .iter()
+            .filter(|(_, p)| p.expires <= now)
+            .map(|(challenge, _)| challenge.clone())
+            .collect();
+        pending_logins.insert(challenge.clone(), pending);
+        expired
+            .iter()
+            .filter_map(|challenge| pending_logins.remove(challenge))
+            .collect()
+    };
+    for pending in expired {
+        revoke_held_back(auth, pending);
+    }
+
+    let challenge = ChallengePayload { challenge };
+    if enrolled {
+        info!("User {:?} needs to provide a TOTP code", id);
+        Err(GateError::TotpRequired(challenge).into())
+    } else {
+        info!("User {:?} needs to enroll TOTP", id);
+        Err(GateError::TotpEnrollmentRequired(challenge).into())
+    }
+}
+
+/// End the session held back by a pending login which is dropped
+fn revoke_held_back(auth: &AuthClient, pending: PendingLogin) {
+    match auth.deauthenticate(pending.context.token) {
+        Ok(()) => debug!("Ended held back session of user {:?}", pending.id),
+        Err(e) => warn!("Unable to end held back session of user {:?}: {:?}", pending.id, e),
+    }
+}
+
+/// Get the user id of a pending login without consuming it
+fn pending_login_user(challenge: &str) -> Result<UserId, ApiError> {
+    PENDING_LOGINS
+        .read()
+        .map_err(|_| ResponseError::InternalServerError)?
+        .get(challenge)
+        .filter(|p| p.expires > Utc::now())
+        .map(|p| p.id)
+        .ok_or_else(|| ResponseError::Unauthenticated.into())
+}
+
+/// Remove a pending login, returning the held back session
+fn take_pending_login(
+    auth: &AuthClient,
+    challenge: &str,
+) -> Result<SessionContextPayload, ApiError> {
+    let pending = PENDING_LOGINS
+        .write()
+        .map_err(|_| ResponseError::InternalServerError)?
+        .remove(challenge)
+        .ok_or(ResponseError::Unauthenticated)?;
+    if pending.expires <= Utc::now() {
+        revoke_held_back(auth, pending);
+        return Err(ResponseError::Unauthenticated.into());
+    }
+    Ok(pending.context)
+}
+
+/// Count a wrong code for a pending login, dropping it after too many
+fn fail_pending_login(auth: &AuthClient, challenge: &str) {
+    let exhausted = match PENDING_LOGINS.write() {
+        Ok(mut pending_logins) => {
+            let exhausted = pending_logins.get_mut(challenge).map_or(false, |p| {
+                p.attempts += 1;
+                p.attempts >= MAX_ATTEMPTS
+            });
+            if exhausted {
+                pending_logins.remove(challenge)
+            } else {
+                None
+            }
+        }
+        Err(e) => {
+            error!("Error writing to pending logins: {}", e);
+            None
+        }
+    };
+    if let Some(pending) = exhausted {
+        warn!("Too many wrong TOTP codes, dropping login challenge");
+        revoke_held_back(auth, pending);
+    }
+}
+
+/// Percent-encode everything except unreserved characters (RFC 3986)
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        }).collect()
+}
+
+/// Requests to log in with, enroll or disable two-factor authentication
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TotpRequest {
+    VerifyLogin(VerifyLoginPayload),
+    BeginEnrollment(BeginEnrollmentPayload),
+    ConfirmEnrollment(ConfirmEnrollmentPayload),
+    Disable(DisablePayload),
+}
+
+/// Answer to a `TOTP_REQUIRED` challenge
+#[derive(Deserialize, Debug)]
+pub struct VerifyLoginPayload {
+    pub challenge: String,
+    pub code: String,
+}
+
+/// Start enrolling, either logged in or with a `TOTP_ENROLLMENT_REQUIRED`
+/// challenge
+#[derive(Deserialize, Debug)]
+pub struct BeginEnrollmentPayload {
+    pub challenge: Option<String>,
+}
+
+/// Finish enrolling with the first code generated from the new secret
+#[derive(Deserialize, Debug)]
+pub struct ConfirmEnrollmentPayload {
+    pub challenge: Option<String>,
+    pub code: String,
+}
+
+/// Disable two-factor authentication with a current code
+#[derive(Deserialize, Debug)]
+pub struct DisablePayload {
+    pub code: String,
+}
+
+/// A new secret which has not been confirmed yet
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EnrollmentPayload {
+    pub secret: String,
+    pub provisioning_uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// The outcome of a successful `TotpRequest`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "payload", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TotpSuccess {
+    Authenticated,
+    Enrollment(EnrollmentPayload),
+    TotpEnabled,
+    TotpDisabled,
+}
+
+/// Log in with, enroll or disable two-factor authentication
+///
+/// # Request objects
+///
+/// ## Finish a login
+///
+/// Answers the challenge from a `TOTP_REQUIRED` error with a code from the
+/// authenticator app or one of the recovery codes. Sets the session cookie.
+///
+/// ```json
+/// {
+///     "type": "VERIFY_LOGIN",
+///     "payload": {
+///         "challenge": "2f1b6c...",
+///         "code": "123456"
+///     }
+/// }
+/// ```
+///
+/// ## Begin enrollment
+///
+/// Either logged in, or with the challenge from a `TOTP_ENROLLMENT_REQUIRED`
+/// error. Returns a new secret, a provisioning URI (e.g. to show as a QR code)
+/// and recovery codes, which are only shown once.
+///
+/// ```json
+/// {
+///     "type": "BEGIN_ENROLLMENT",
+///     "payload": {}
+/// }
+/// ```
+///
+/// ## Confirm enrollment
+///
+/// Enables two-factor authentication if the code matches the new secret.
+/// When enrolling during a login this also sets the session cookie.
+///
+/// ```json
+/// {
+///     "type": "CONFIRM_ENROLLMENT",
+///     "payload": {
+///         "code": "123456"
+///     }
+/// }
+/// ```
+///
+/// ## Disable
+///
+/// Not possible if the role of the user requires two-factor authentication.
+///
+/// After too many wrong codes of a user all of these fail with
+/// `TOTP_LOCKED_OUT` for a while.
+///
+/// ```json
+/// {
+///     "type": "DISABLE",
+///     "payload": {
+///         "code": "123456"
+///     }
+/// }
+/// ```
+#[post("/auth/totp", format = "application/json", data = "<req>")]
+pub fn totp(
+    user: Option<AuthenticatedUser>,
+    mut cookies: Cookies,
+    req: Option<Json<TotpRequest>>,
+) -> ApiResult<TotpSuccess> {
+    use self::TotpRequest::*;
+
+    let req = req.ok_or(AuthError::InvalidCredentials)?; // If invalid request query.
+    let auth = connect_to_auth()?;
+
+    match req.into_inner() {
+        VerifyLogin(p) => {
+            let id = pending_login_user(&p.challenge)?;
+            if let Err(e) = verify(&auth, id, &p.code) {
+                fail_pending_login(&auth, &p.challenge);
+                return Err(e);
+            }
+
+            let context = take_pending_login(&auth, &p.challenge)?;
+            auth::start_session(&auth, &mut cookies, context);
+            info!("User {:?} authenticated successfully with TOTP", id);
+            Ok(Json(TotpSuccess::Authenticated))
+        }
+        BeginEnrollment(p) => {
+            let id = match (p.challenge, user) {
+                (Some(challenge), _) => pending_login_user(&challenge)?,
+                (None, Some(user)) => {
+                    user.session_token()?;
+                    user.id
+                }
+                (None, None) => return Err(ResponseError::Unauthenticated.into()),
+            };
+            if auth.get_totp(id)?.is_some() {
+                warn!("User {:?} tried to enroll TOTP twice", id);
+                return Err(ResponseError::Unauthorized.into());
+            }
+
+            let username = connect_to_controller()?
+                .get_user(GetUserPayload { id })?
+                .username;
+
+            let secret = base32::encode(
+                base32::Alphabet::RFC4648 { padding: false },
+                &crypto::random_bytes(20),
+            );
+            let recovery_codes: Vec<String> = (0..RECOVERY_CODES)
+                .map(|_| {
+                    let code = crypto::random_hex(5);
+                    format!("{}-{}", &code[..5], &code[5..])
+                }).collect();
+            let provisioning_uri = format!(
+                "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&digits={digits}&period={period}",
+                issuer = percent_encode(&ISSUER),
+                user = percent_encode(&username.to_string()),
+                secret = secret,
+                digits = DIGITS,
+                period = STEP
+            );
+
+            let pending = PendingEnrollment {
+                totp: TotpPayload {
+                    secret: secret.clone(),
+                    recovery_codes: recovery_codes.iter().map(|c| hash_recovery_code(c)).collect(),
+                },
+                expires: Utc::now() + Duration::minutes(10),
+            };
+            PENDING_ENROLLMENTS
+                .write()
+                .map_err(|_| ResponseError::InternalServerError)?
+                .insert(id, pending);
+
+            info!("User {:?} began TOTP enrollment", id);
+            Ok(Json(TotpSuccess::Enrollment(EnrollmentPayload {
+                secret,
+                provisioning_uri,
+                recovery_codes,
+            })))
+        }
+        ConfirmEnrollment(p) => {
+            let id = match (&p.challenge, user) {
+                (Some(challenge), _) => pending_login_user(challenge)?,
+                (None, Some(user)) => {
+                    user.session_token()?;
+                    user.id
+                }
+                (None, None) => return Err(ResponseError::Unauthenticated.into()),
+            };
+
+            let pending = PENDING_ENROLLMENTS
+                .write()
+                .map_err(|_| ResponseError::InternalServerError)?
+                .remove(&id)
+                .filter(|p| p.expires > Utc::now())
+                .ok_or(ResponseError::Unauthorized)?;
+
+            if let Err(e) = verify_counted(id, &pending.totp.secret, &p.code) {
+                // Keep the enrollment so the user can try again
+                PENDING_ENROLLMENTS
+                    .write()
+                    .map_err(|_| ResponseError::InternalServerError)?
+                    .insert(id, pending);
+                if let Some(challenge) = &p.challenge {
+                    fail_pending_login(&auth, challenge);
+                }
+                return Err(e);
+            }
+
+            auth.set_totp(SetTotpPayload {
+                id,
+                totp: Some(pending.totp),
+            })?;
+            info!("User {:?} enabled TOTP", id);
+
+            match p.challenge {
+                Some(challenge) => {
+                    let context = take_pending_login(&auth, &challenge)?;
+                    auth::start_session(&auth, &mut cookies, context);
+                    info!("User {:?} authenticated successfully with TOTP", id);
+                    Ok(Json(TotpSuccess::Authenticated))
+                }
+                None => Ok(Json(TotpSuccess::TotpEnabled)),
+            }
+        }
+        Disable(p) => {
+            let user = user.ok_or(ResponseError::Unauthenticated)?;
+            user.session_token()?;
+            if is_required_for(user.role) {
+                warn!(
+                    "User {:?} tried to disable TOTP required for {:?}",
+                    user.id, user.role
+                );
+                return Err(ResponseError::Unauthorized.into());
+            }
+
+            verify(&auth, user.id, &p.code)?;
+            auth.set_totp(SetTotpPayload {
+                id: user.id,
+                totp: None,
+            })?;
+            info!("User {:?} disabled TOTP", user.id);
+            Ok(Json(TotpSuccess::TotpDisabled))
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Keeping users consistent between the auth service and the controller.
+//!
+//! A user exists twice: with credentials in the auth service and with a
+//! profile in the controller. Creating a user is therefore treated as a saga.
+//! The controller step is retried after a short backoff
+//! (`REGISTRATION_RETRIES` times, default 3) if the controller could not be
+//! reached. If it still fails, and
+//! the controller does not have the user after all, the auth user is deleted
+//! again, so no half-created account is left behind.
+//!
+//! If even the rollback fails, or the services got out of sync in some other
+//! way, `reconcile` finds and repairs users which only exist in one service.
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+use datatypes::content::requests::{AddUserPayload, GetUserPayload};
+use datatypes::content::responses::UserPayload;
+use datatypes::error::ResponseError;
+use datatypes::valid::ids::UserId;
+
+use crate::auth::connect_to_auth;
+use crate::comms::auth::SyncClient as AuthClient;
+use crate::content::connect_to_controller;
+
+lazy_static! {
+    static ref RETRIES: u32 = match std::env::var("REGISTRATION_RETRIES") {
+        Ok(value) => value.parse::<u32>().unwrap_or_else(|_| {
+            warn!("REGISTRATION_RETRIES is not a number, using '3'");
+            3
+        }),
+        Err(_) => {
+            warn!("REGISTRATION_RETRIES is not set, using '3'");
+            3
+        }
+    };
+}
+
+/// Wait before the first retry, doubled for every further retry
+const BACKOFF_MILLIS: u64 = 100;
+
+/// Add a user the auth service just registered to the controller
+///
+/// Only failures to reach the controller are retried, anything the controller
+/// answered would be answered the same way again.
+fn add_to_controller(user: AddUserPayload) -> Result<UserPayload, ResponseError> {
+    let mut attempt = 0;
+    loop {
+        let result = connect_to_controller()
+            .map_err(|e| (true, e))
+            .and_then(|controller| {
+                controller.add_user(user.clone()).map_err(|e| {
+                    error!("Controller: Unable to add user: {:?}", e);
+                    let transient = match e {
+                        tarpc::Error::Io(_) => true,
+                        _ => false,
+                    };
+                    (transient, e.into())
+                })
+            });
+
+        match result {
+            Ok(user) => return Ok(user),
+            Err((true, _)) if attempt < *RETRIES => {
+                thread::sleep(Duration::from_millis(BACKOFF_MILLIS << attempt.min(6)));
+                attempt += 1;
+                warn!("Retrying 'add_user' ({}/{})", attempt, *RETRIES);
+            }
+            Err((_, e)) => return Err(e),
+        }
+    }
+}
+
+/// Finish creating a user which the auth service has registered
+///
+/// If the controller does not accept the user the auth user is rolled back,
+/// and the original error is returned. An `add_user` whose reply got lost may
+/// still have succeeded, so the controller is asked for the user first, and
+/// nothing is rolled back if that is not possible either.
+pub fn provision(auth: &AuthClient, user: AddUserPayload) -> Result<UserPayload, ResponseError> {
+    let id = user.id;
+    let e = match add_to_controller(user) {
+        Ok(user) => return Ok(user),
+        Err(e) => e,
+    };
+
+    match connect_to_controller().map(|controller| controller.get_user(GetUserPayload { id })) {
+        Ok(Ok(user)) => {
+            info!("User ({:?}) was added to the controller after all", id);
+            return Ok(user);
+        }
+        Ok(Err(tarpc::Error::App(_))) => {}
+        _ => {
+            error!(
+                "Unable to tell whether user ({:?}) was added, run 'user reconcile'",
+                id
+            );
+            return Err(e);
+        }
+    }
+
+    match auth.delete_user(id) {
+        Ok(()) => info!("Rolled back registration of user ({:?})", id),
+        Err(rollback) => error!(
+            "Unable to roll back registration of user ({:?}), run 'user reconcile': {:?}",
+            id, rollback
+        ),
+    }
+    Err(e)
+}
+
+/// Remove a user from both services again, e.g. when creating it could not
+/// be finished
+///
+/// The credentials go first, so the user can no longer log in even if the
+/// profile stays behind.
+pub fn remove(auth: &AuthClient, id: UserId) -> Result<(), String> {
+    auth.delete_user(id)
+        .map_err(|e| format!("Unable to delete user from the auth service: {:?}", e))?;
+    connect_to_controller()
+        .map_err(|e| format!("Unable to connect to the controller: {:?}", e))?
+        .delete_user(id)
+        .map_err(|e| format!("Unable to delete user from the controller: {:?}", e))?;
+    info!("Removed user ({:?})", id);
+    Ok(())
+}
+
+/// The outcome of a reconciliation run
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    /// Users only known to the auth service which were added to the controller
+    pub repaired: Vec<UserId>,
+    /// Users only known to the controller which were removed from it. They
+    /// have no credentials, so nobody could log in as them.
+    pub removed: Vec<UserId>,
+    /// Users which could not be repaired or removed
+    pub failed: Vec<UserId>,
+}
+
+/// Find users which only exist in one of the services and repair them
+///
+/// Users only known to the auth service are added to the controller, users
+/// only known to the controller are removed from it. With `dry_run` nothing is
+/// changed, users which would be repaired or removed are only reported.
+///
+/// Users are registered with the auth service first, so the controller is
+/// listed first. A user registering meanwhile then shows up in the auth
+/// service, not only in the controller. Before a user is removed, the auth
+/// service is asked once more whether it really does not know the user.
+pub fn reconcile(dry_run: bool) -> Result<ReconcileReport, String> {
+    let controller = connect_to_controller()
+        .map_err(|e| format!("Unable to connect to the controller: {:?}", e))?;
+    let controller_users = controller
+        .get_all_users(())
+        .map_err(|e| format!("Unable to get users from the controller: {:?}", e))?;
+    let auth = connect_to_auth()
+        .map_err(|e| format!("Unable to connect to the auth service: {:?}", e))?;
+    let auth_users = auth
+        .get_all_users(())
+        .map_err(|e| format!("Unable to get users from the auth service: {:?}", e))?;
+
+    let in_auth: HashSet<UserId> = auth_users.iter().map(|u| u.id).collect();
+    let in_controller: HashSet<UserId> = controller_users.iter().map(|u| u.id).collect();
+
+    let mut report = ReconcileReport::default();
+    for user in auth_users {
+        if in_controller.contains(&user.id) {
+            continue;
+        }
+
+        let id = user.id;
+        if dry_run {
+            report.repaired.push(id);
+            continue;
+        }
+        match add_to_controller(user) {
+            Ok(_) => {
+                info!("Reconcile: added user ({:?}) to the controller", id);
+                report.repaired.push(id);
+            }
+            Err(e) => {
+                error!("Reconcile: unable to add user ({:?}): {:?}", id, e);
+                report.failed.push(id);
+            }
+        }
+    }
+
+    for id in controller_users.iter().map(|u| u.id) {
+        if in_auth.contains(&id) {
+            continue;
+        }
+
+        match auth.get_user_role(id) {
+            Ok(_) => {
+                info!("Reconcile: user ({:?}) was registered meanwhile", id);
+                continue;
+            }
+            Err(tarpc::Error::App(_)) => {}
+            Err(e) => {
+                error!("Reconcile: unable to check user ({:?}): {:?}", id, e);
+                report.failed.push(id);
+                continue;
+            }
+        }
+
+        warn!("Reconcile: user ({:?}) only exists in the controller", id);
+        if dry_run {
+            report.removed.push(id);
+            continue;
+        }
+        match controller.delete_user(id) {
+            Ok(()) => {
+                info!("Reconcile: removed user ({:?}) from the controller", id);
+                report.removed.push(id);
+            }
+            Err(e) => {
+                error!("Reconcile: unable to remove user ({:?}): {:?}", id, e);
+                report.failed.push(id);
+            }
+        }
+    }
+
+    Ok(report)
+}